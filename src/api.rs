@@ -0,0 +1,32 @@
+/*
+ * HTTP/JSON API for controlling the robot
+ */
+
+extern crate pulurobot_robot;
+
+use std::env;
+use std::process;
+
+use pulurobot_robot::pulurobot::{Robot, PuluRobot, serve_http};
+
+fn main() {
+    let addr = match env::args().nth(1) {
+        Some(s) => s,
+        None => String::from("127.0.0.1:8080"),
+    };
+
+    let robot = match Robot::from_config("config/config") {
+        Ok(s) => s,
+        Err(e) => {
+            eprintln!("{}", e);
+            process::exit(e.exit_code());
+        }
+    };
+
+    println!("Serving robot {} on http://{}", robot.config.name, addr);
+
+    if let Err(e) = serve_http(robot, &addr) {
+        eprintln!("Unable to serve http: {}", e);
+        process::exit(e.exit_code());
+    }
+}
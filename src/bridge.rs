@@ -0,0 +1,47 @@
+/*
+ * Bridge between the robot and the fleet server
+ */
+
+extern crate pulurobot_robot;
+
+use std::process;
+use std::thread;
+
+use pulurobot_robot::pulurobot::{Robot, PuluRobot, run_bridge};
+
+fn main() {
+    let mut robot = match Robot::from_config("config/config") {
+        Ok(s) => s,
+        Err(e) => {
+            eprintln!("{}", e);
+            process::exit(e.exit_code());
+        }
+    };
+
+    if robot.config.server_address.is_empty() || robot.config.server_port.is_empty() {
+        eprintln!("No server configured, run the setup tool first");
+        process::exit(3);
+    }
+
+    let scheme = if robot.config.server_tls { "wss" } else { "ws" };
+    let url = format!("{}://{}:{}", scheme, robot.config.server_address, robot.config.server_port);
+
+    println!("Bridging robot {} to {}", robot.config.name, url);
+
+    if !robot.config.server_tls {
+        println!("The connection to the server is not encrypted, set server_tls in the config file to use wss://");
+    }
+
+    // Report when the connection to the robot drops and comes back
+    let link_events = robot.subscribe_link();
+    thread::spawn(move || {
+        for state in link_events {
+            println!("Robot connection: {:?}", state);
+        }
+    });
+
+    if let Err(e) = run_bridge(robot, &url) {
+        eprintln!("{}", e);
+        process::exit(e.exit_code());
+    }
+}
@@ -1,3 +1,16 @@
+#[macro_use]
+extern crate serde_derive;
+extern crate serde;
+extern crate serde_json;
+extern crate byteorder;
+extern crate ws;
+extern crate url;
+extern crate hmac;
+extern crate sha2;
+extern crate rand;
+
+pub mod pulurobot;
+
 #[cfg(test)]
 mod tests {
     #[test]
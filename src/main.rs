@@ -4,116 +4,574 @@
  */
 
 
-#[macro_use]
-extern crate serde_derive;
-extern crate serde;
+extern crate pulurobot_robot;
 extern crate serde_json;
-extern crate byteorder;
-
-mod pulurobot;
-
-use pulurobot::{Robot, PuluRobot};
-use std::net::{TcpStream, Shutdown};
+extern crate rustyline;
+
+use pulurobot_robot::pulurobot::{Robot, PuluRobot, RobotError, RobotErrorType, Config, ConfigErrorType, Message, MessageRecord, LinkState, BatteryStatus, ConfigHandler, Mission, MissionEvent, GotoOutcome, Script, ProtocolStats};
+use rustyline::{Editor, Context, Helper};
+use rustyline::completion::{Completer, FilenameCompleter, Pair};
+use rustyline::error::ReadlineError;
+use rustyline::highlight::Highlighter;
+use rustyline::hint::Hinter;
+use rustyline::history::DefaultHistory;
+use rustyline::validate::Validator;
+use std::env;
+use std::path::{Path, PathBuf};
+use std::fs::File;
+use std::process;
 use std::io;
-use std::io::{BufWriter,BufReader,BufRead,Write,Read};
-use byteorder::{BigEndian, ReadBytesExt};
+use std::io::{BufWriter,BufRead,Write};
 use std::thread;
+use std::time::Duration;
 use std::sync::mpsc::{self, TryRecvError};
 
+/* What `listen` prints */
+struct ListenOptions {
+    /* One json object per message, instead of text */
+    json: bool,
+    /* Only messages with these opcodes, or all if empty */
+    opcodes: Vec<u8>,
+}
+
+/* Commands run_command knows, besides listen */
+const COMMANDS: &[&str] = &["free", "localize", "stop", "pose", "battery", "state", "stats", "save", "goto", "forward",
+                            "turn", "move", "heightmap", "list", "record", "mission", "run", "rename", "delete"];
+
+/* Pixels per cell in images written by `heightmap` */
+const HEIGHT_MAP_SCALE: usize = 8;
+
+/* How long `turn` may take */
+const TURN_TIMEOUT_SECS: u64 = 30;
+
+/* Commands only the prompt knows */
+const PROMPT_COMMANDS: &[&str] = &["quit", "help", "listen"];
+
+/* History of the prompt, in the home directory */
+const HISTORY_FILE: &str = ".pulurobot_history";
+
+/* Tab completion at the prompt */
+struct ConsoleHelper {
+    waypoints: Vec<String>,
+    files: FilenameCompleter,
+}
+
+/* Flags given on the command line, and the command to run if any */
+struct Options {
+    config_path: String,
+    /* Host and port overriding the robot address of the config file */
+    robot: Option<(String, String)>,
+    command: Vec<String>,
+}
+
+/* Why a command failed. Decides the exit code when run from the command line */
+enum Failure {
+    /* Wrong command or parameters */
+    Usage(String),
+    /* Unreadable config or mission file, or an unknown waypoint */
+    Config(String),
+    /* Unable to communicate with the robot, with what was being done if not obvious from the error */
+    Robot(RobotError, String),
+    /* The robot didn't manage to do what it was told */
+    Failed(String),
+}
+
+impl Failure {
+    fn message(&self) -> String {
+        match *self {
+            Failure::Usage(ref m) | Failure::Config(ref m) | Failure::Failed(ref m) => m.clone(),
+            Failure::Robot(ref e, ref m) if m.is_empty() => e.to_string(),
+            Failure::Robot(ref e, ref m) => format!("{}: {}", m, e),
+        }
+    }
+
+    /* What to do about it, if anything
+     */
+    fn hint(&self) -> Option<&'static str> {
+        let e = match *self {
+            // Files have nothing to do with the connection
+            Failure::Robot(ref e, _) if e.path.is_none() => e,
+            _ => { return None }
+        };
+
+        match e.err_type {
+            RobotErrorType::Connection => Some("Check that the robot is switched on and reachable, and robot_address and \
+                robot_port in the config file, or pass --robot HOST:PORT"),
+            RobotErrorType::Write | RobotErrorType::Read => Some("The connection to the robot dropped. It is reconnected \
+                in the background, try again in a moment"),
+            RobotErrorType::Timeout => Some("The robot is connected but didn't report in time. Check that its software is \
+                running, and 'stats' for frames that couldn't be decoded"),
+            RobotErrorType::Protocol => Some("The robot sent something this version doesn't understand. 'record FILE' \
+                captures it for a bug report"),
+            RobotErrorType::Authentication => Some("Set private_key in the config file, e.g. with the setup tool"),
+            RobotErrorType::NotYetImplemented | RobotErrorType::NotArrived | RobotErrorType::Config => None,
+        }
+    }
+
+    /* The message and hint, as printed when a command fails
+     */
+    fn report(&self) -> String {
+        match self.hint() {
+            Some(hint) => format!("{}\n{}", self.message(), hint),
+            None => self.message(),
+        }
+    }
+
+    fn exit_code(&self) -> i32 {
+        match *self {
+            Failure::Failed(_) => 1,
+            Failure::Usage(_) => 2,
+            Failure::Config(_) => 3,
+            Failure::Robot(ref e, _) => e.exit_code(),
+        }
+    }
+}
+
+fn send_failed(e: RobotError) -> Failure {
+    Failure::Robot(e, String::from("Unable to send command to robot"))
+}
+
 fn main() {
+    let args: Vec<String> = env::args().skip(1).collect();
+
+    let options = match parse_options(&args) {
+        Ok(o) => o,
+        Err(f) => {
+            eprintln!("{}", f.report());
+            process::exit(f.exit_code());
+        }
+    };
+
+    if options.command.is_empty() {
+        run_interactive(&options);
+    } else {
+        process::exit(run_command_line(&options));
+    }
+}
+
+/* Splits the flags from the command. Flags after the command belong to it
+ */
+fn parse_options(args: &[String]) -> Result<Options, Failure> {
+    let mut options = Options {
+        config_path: String::from("config/config"),
+        robot: None,
+        command: Vec::new(),
+    };
+
+    let mut args = args.iter();
+
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--config" => match args.next() {
+                Some(path) => options.config_path = path.to_owned(),
+                None => { return Err(Failure::Usage(String::from("Flag '--config' takes a PATH"))) }
+            },
+            "--robot" => match args.next() {
+                Some(addr) => options.robot = Some(parse_robot(addr)?),
+                None => { return Err(Failure::Usage(String::from("Flag '--robot' takes HOST:PORT"))) }
+            },
+            _ => {
+                options.command.push(arg.to_owned());
+                options.command.extend(args.cloned());
+                break;
+            }
+        }
+    }
+
+    Ok(options)
+}
+
+/* Splits the HOST:PORT of --robot, at the last colon for IPv6 addresses like [::1]:22222
+ */
+fn parse_robot(addr: &str) -> Result<(String, String), Failure> {
+    match addr.rfind(':') {
+        Some(i) if i > 0 && addr[i + 1..].parse::<u16>().is_ok() => Ok((addr[..i].to_owned(), addr[i + 1..].to_owned())),
+        _ => Err(Failure::Usage(format!("Expected HOST:PORT, got: {}", addr))),
+    }
+}
+
+/* Reads the config file, applies the flags, and connects to the robot
+ */
+fn connect(options: &Options) -> Result<Robot, Failure> {
+    let mut config = match Config::from_file(&options.config_path) {
+        Ok(c) => c,
+        Err(ref e) if e.err_type == ConfigErrorType::FileNotFound => {
+            return Err(Failure::Config(format!("{}\nCreate it with the setup tool, or pass --config PATH", e)))
+        },
+        Err(e) => { return Err(Failure::Config(e.to_string())) }
+    };
+
+    if let Some((ref host, ref port)) = options.robot {
+        config.robot_address = host.clone();
+        config.robot_port = port.clone();
+    }
+
+    match Robot::with_config(config, &options.config_path) {
+        Ok(r) => Ok(r),
+        Err(e) => Err(Failure::Robot(e, String::new())),
+    }
+}
+
+/* Runs a single command given on the command line, returning the exit code
+ */
+fn run_command_line(options: &Options) -> i32 {
+    let input: Vec<&str> = options.command.iter().map(|s| s.as_str()).collect();
+
+    if input[0] == "help" {
+        handle_help();
+        return 0;
+    }
+
+    // Parse before connecting, to report usage errors as such
+    if input[0] != "listen" && !COMMANDS.contains(&input[0]) {
+        eprintln!("Unknown command: {}", input[0]);
+        return 2;
+    }
+
+    let listen_options = if input[0] == "listen" {
+        match parse_listen_options(&input[1..]) {
+            Ok(o) => Some(o),
+            Err(e) => {
+                eprintln!("{}", e);
+                return 2;
+            }
+        }
+    } else {
+        None
+    };
+
+    let mut robot = match connect(options) {
+        Ok(r) => r,
+        Err(f) => {
+            eprintln!("{}", f.report());
+            return f.exit_code();
+        }
+    };
+
+    // Streams messages until killed
+    if let Some(listen_options) = listen_options {
+        let stdout = io::stdout();
+        let mut out = stdout.lock();
+
+        // Stop quietly once the reader goes away, e.g. `| head`
+        for message in robot.subscribe() {
+            if print_message(&mut out, &message, &listen_options).is_err() {
+                break;
+            }
+        }
+
+        return 0;
+    }
+
+    match run_command(&mut robot, &input) {
+        Ok(_) => 0,
+        Err(f) => {
+            eprintln!("{}", f.report());
+            f.exit_code()
+        }
+    }
+}
+
+fn run_interactive(options: &Options) {
 
     let mut running = true;
 
     let mut io_writer = BufWriter::new(io::stdout());
 
     // Setup and test connection
-    io_writer.write("Testing connection to robot...".as_bytes()).unwrap();
+    io_writer.write_all("Testing connection to robot...".as_bytes()).unwrap();
     io_writer.flush().unwrap();
 
-    let mut robot = match Robot::from_config("config/config") {
+    let mut robot = match connect(options) {
         Ok(s) => { 
-            io_writer.write("OK\n".as_bytes()).unwrap(); 
+            io_writer.write_all("OK\n".as_bytes()).unwrap(); 
             io_writer.flush().unwrap();
             s
         },
-        Err(_) => {
-            io_writer.write("FAILED\n".as_bytes()).unwrap();
+        Err(f) => {
+            io_writer.write_all("FAILED\n".as_bytes()).unwrap();
             io_writer.flush().unwrap();
-            panic!("Unable to connect to robot")
+            eprintln!("{}", f.report());
+            process::exit(f.exit_code());
+        }
+    };
+
+    // Report when the connection to the robot drops and comes back
+    let link_events = robot.subscribe_link();
+    thread::spawn(move || {
+        for state in link_events {
+            match state {
+                LinkState::Connected => println!("\nReconnected to robot"),
+                LinkState::Disconnected => println!("\nLost connection to robot, reconnecting..."),
+                LinkState::Retrying { .. } => {},
+            }
+        }
+    });
+
+    let mut editor: Editor<ConsoleHelper, DefaultHistory> = match Editor::new() {
+        Ok(e) => e,
+        Err(e) => {
+            eprintln!("Unable to set up the prompt: {}", e);
+            process::exit(1);
         }
     };
 
-    robot.disconnect();
+    editor.set_helper(Some(ConsoleHelper::new(&robot)));
 
-    let mut io_reader = BufReader::new(io::stdin());
-    let mut io_buf = String::new();
+    let history_path = history_path();
+    // No history yet on the first run
+    let _ = editor.load_history(&history_path);
 
-    print!("\n");
+    println!();
     while running {
-        io_buf.clear();
+        let line = match editor.readline("> ") {
+            Ok(line) => line,
+            Err(ReadlineError::Interrupted) => {
+                println!("Type 'quit' or press Ctrl-D to exit");
+                continue;
+            },
+            Err(ReadlineError::Eof) => {
+                println!("Bye!");
+                break;
+            },
+            Err(e) => {
+                eprintln!("Unable to read input: {}", e);
+                break;
+            },
+        };
 
-        io_writer.write("> ".as_bytes()).unwrap();
-        io_writer.flush().unwrap();
+        let input: Vec<&str> = line.split_whitespace().collect();
 
-        io_reader.read_line(&mut io_buf).unwrap();
+        if input.is_empty() {
+            continue;
+        }
 
-        // Remove trailing newline
-        io_buf.pop();
-        let input: Vec<&str> = io_buf.split(" ").collect();
+        let _ = editor.add_history_entry(line.trim());
+        // Saved every time, as the console is often killed rather than quit
+        let _ = editor.save_history(&history_path);
 
         // Handle input commands
         match input[0] {
             "quit" => { println!("Bye!"); running = false; },
             "help" => handle_help(),
-            "listen" => handle_listen(&mut robot), 
-            "free" => {
-                match robot.free() {
-                    Ok(_) => (),
-                    Err(_) => println!("Unable to send command to robot"),
+            "listen" => handle_listen(&mut robot, &input[1..]),
+            _ => {
+                if let Err(f) = run_command(&mut robot, &input) {
+                    println!("{}", f.report());
                 }
             },
-            "localize" => {
-                match robot.localize() {
-                    Ok(_) => (),
-                    Err(_) => println!("Unable to send command to robot"),
+        }
+
+        // Waypoints may have been saved, renamed or deleted
+        if let Some(helper) = editor.helper_mut() {
+            *helper = ConsoleHelper::new(&robot);
+        }
+    }
+}
+
+/* Where the prompt keeps its history between runs: in the home directory, or else the
+ * working directory
+ */
+fn history_path() -> PathBuf {
+    match env::var_os("HOME") {
+        Some(home) => Path::new(&home).join(HISTORY_FILE),
+        None => PathBuf::from(HISTORY_FILE),
+    }
+}
+
+impl ConsoleHelper {
+    fn new(robot: &Robot) -> ConsoleHelper {
+        ConsoleHelper {
+            waypoints: robot.config.waypoints.keys().cloned().collect(),
+            files: FilenameCompleter::new(),
+        }
+    }
+}
+
+/* Completes the command, then waypoint names after goto, rename and delete, and file
+ * names after commands taking a FILE
+ */
+impl Completer for ConsoleHelper {
+    type Candidate = Pair;
+
+    fn complete(&self, line: &str, pos: usize, ctx: &Context) -> rustyline::Result<(usize, Vec<Pair>)> {
+        let start = line[..pos].rfind(char::is_whitespace).map_or(0, |i| i + 1);
+        let before: Vec<&str> = line[..start].split_whitespace().collect();
+        let word = &line[start..pos];
+
+        let candidates: Vec<&str> = match before.as_slice() {
+            [] => PROMPT_COMMANDS.iter().chain(COMMANDS.iter()).cloned().collect(),
+            ["goto"] | ["rename"] | ["delete"] => self.waypoints.iter().map(|w| w.as_str()).collect(),
+            ["mission"] | ["run"] | ["record"] => { return self.files.complete(line, pos, ctx) },
+            _ => Vec::new(),
+        };
+
+        let pairs = candidates.into_iter()
+            .filter(|c| c.starts_with(word))
+            .map(|c| Pair { display: c.to_owned(), replacement: format!("{} ", c) })
+            .collect();
+
+        Ok((start, pairs))
+    }
+}
+
+impl Hinter for ConsoleHelper {
+    type Hint = String;
+}
+
+impl Highlighter for ConsoleHelper {}
+
+impl Validator for ConsoleHelper {}
+
+impl Helper for ConsoleHelper {}
+
+/* Runs any command but quit, help and listen, which depend on how the console runs
+ */
+fn run_command(robot: &mut Robot, input: &[&str]) -> Result<(), Failure> {
+    match input[0] {
+        "free" => robot.free().map_err(send_failed),
+        "localize" => robot.localize().map_err(send_failed),
+        "stop" => robot.stop().map_err(send_failed),
+        "pose" => {
+            match robot.get_pose() {
+                Ok(pose) => { println!("x={} y={} angle={:.1}", pose.x, pose.y, pose.angle.degrees()); Ok(()) },
+                Err(e) => Err(Failure::Robot(e, String::from("Unable to read position from robot"))),
+            }
+        },
+        "battery" => {
+            match robot.get_battery() {
+                Ok(battery) => { println!("{}", format_battery(&battery)); Ok(()) },
+                Err(e) => Err(Failure::Robot(e, String::from("Unable to read battery status from robot"))),
+            }
+        },
+        "state" => {
+            match robot.get_state() {
+                Ok(state) => { println!("{:?}", state); Ok(()) },
+                Err(e) => Err(Failure::Robot(e, String::from("Unable to read state from robot"))),
+            }
+        },
+        "stats" => {
+            print_protocol_stats(&robot.get_protocol_stats());
+            Ok(())
+        },
+        "save" => {
+            if input.len() >= 2 {
+                let with_heading = input[2..].contains(&"--heading");
+                let tags = input[2..].iter().filter(|t| **t != "--heading").map(|t| t.to_string()).collect();
+
+                match robot.save_waypoint(input[1], tags, with_heading) {
+                    Ok(_) => Ok(()),
+                    Err(e) => Err(Failure::Robot(e, String::from("Unable to save waypoint"))),
                 }
-            },
-            "stop" => {
-                match robot.stop() {
-                    Ok(_) => (),
-                    Err(_) => println!("Unable to send command to robot"),
+            } else {
+                Err(Failure::Usage(String::from("Command 'save' takes at least 1 parameter")))
+            }
+        },
+        "goto" => handle_goto(robot, &input[1..]),
+        "forward" => {
+            match (input.len(), input.get(1).map(|d| d.parse()), input.get(2).cloned()) {
+                (2, Some(Ok(distance)), None) => handle_forward(robot, distance, false),
+                (3, Some(Ok(distance)), Some("force")) => handle_forward(robot, distance, true),
+                _ => Err(Failure::Usage(String::from("Command 'forward' takes a distance in whole mm, negative to back up, and optionally 'force'"))),
+            }
+        },
+        "turn" => {
+            match (input.len(), input.get(1).map(|d| d.parse())) {
+                (2, Some(Ok(degrees))) => {
+                    robot.turn(degrees, Duration::new(TURN_TIMEOUT_SECS, 0))
+                        .map_err(|e| Failure::Robot(e, String::from("Unable to turn the robot")))
+                },
+                _ => Err(Failure::Usage(String::from("Command 'turn' takes a number of degrees, positive to the left"))),
+            }
+        },
+        "move" => {
+            match (input.len(), input.get(1).map(|d| d.parse()), input.get(2).map(|d| d.parse())) {
+                (3, Some(Ok(dx)), Some(Ok(dy))) => robot.move_by(dx, dy).map_err(send_failed),
+                _ => Err(Failure::Usage(String::from("Command 'move' takes DX DY in whole mm"))),
+            }
+        },
+        "heightmap" => {
+            if input.len() <= 2 {
+                handle_height_map(robot, input.get(1).cloned())
+            } else {
+                Err(Failure::Usage(String::from("Command 'heightmap' takes at most 1 parameter")))
+            }
+        },
+        "list" => { handle_list(robot); Ok(()) },
+        "record" => {
+            if input.len() == 2 && input[1] == "stop" {
+                robot.stop_capture();
+                println!("Stopped recording");
+                Ok(())
+            } else if input.len() == 2 {
+                match robot.start_capture(input[1]) {
+                    Ok(_) => { println!("Recording to {}, 'record stop' to stop", input[1]); Ok(()) },
+                    Err(e) => Err(Failure::Robot(e, String::from("Unable to record"))),
                 }
-            },
-            "save" => {
-                if input.len() == 2 {
-                    match input[1] {
-                        "a" => robot.save_location("a"),
-                        "b" => robot.save_location("b"),
-                        s => {
-                            println!("Unknown location: {}", s);
-                            Ok(())
-                        },
-                    };
-                } else {
-                    println!("Command 'save' takes 1 parameter");
+            } else {
+                Err(Failure::Usage(String::from("Command 'record' takes 1 parameter")))
+            }
+        },
+        "mission" => {
+            if input.len() == 2 {
+                handle_mission(robot, input[1])
+            } else {
+                Err(Failure::Usage(String::from("Command 'mission' takes 1 parameter")))
+            }
+        },
+        "run" => {
+            if input.len() == 2 {
+                handle_script(robot, input[1])
+            } else {
+                Err(Failure::Usage(String::from("Command 'run' takes 1 parameter")))
+            }
+        },
+        "rename" => {
+            if input.len() == 3 {
+                match robot.config.rename_waypoint(input[1], input[2]) {
+                    Ok(_) => write_config(robot),
+                    Err(e) => Err(Failure::Config(e.to_string())),
                 }
-            },
-            "goto" => {
-                if input.len() == 2 {
-                    match input[1] {
-                        "a" => robot.goto_point("a"),
-                        "b" => robot.goto_point("b"),
-                        s => {
-                            println!("Unknown location: {}", s);
-                            Ok(())
-                        },
-                    };
-                } else {
-                    println!("Command 'goto' takes 1 parameter");
+            } else {
+                Err(Failure::Usage(String::from("Command 'rename' takes 2 parameters")))
+            }
+        },
+        "delete" => {
+            if input.len() == 2 {
+                match robot.config.delete_waypoint(input[1]) {
+                    Ok(_) => write_config(robot),
+                    Err(e) => Err(Failure::Config(e.to_string())),
                 }
-            },
-            s => println!("Unknown command: {}", s),
-        }
+            } else {
+                Err(Failure::Usage(String::from("Command 'delete' takes 1 parameter")))
+            }
+        },
+        s => Err(Failure::Usage(format!("Unknown command: {}", s))),
+    }
+}
+
+/* goto NAME, or goto X Y
+ */
+fn handle_goto(robot: &mut Robot, args: &[&str]) -> Result<(), Failure> {
+    match args.len() {
+        1 => {
+            if robot.config.get_waypoint(args[0]).is_err() {
+                return Err(Failure::Config(format!("Unknown waypoint: {}", args[0])));
+            }
+
+            match robot.goto_waypoint(args[0]) {
+                Ok(_) => Ok(()),
+                Err(ref e) if e.err_type == RobotErrorType::NotArrived => Err(Failure::Failed(e.to_string())),
+                Err(e) => Err(send_failed(e)),
+            }
+        },
+        2 => {
+            match (args[0].parse(), args[1].parse()) {
+                (Ok(x), Ok(y)) => robot.goto(x, y).map_err(send_failed),
+                _ => Err(Failure::Usage(String::from("Coordinates must be whole numbers (mm)"))),
+            }
+        },
+        _ => Err(Failure::Usage(String::from("Command 'goto' takes a waypoint NAME, or X Y"))),
     }
 }
 
@@ -123,111 +581,275 @@ fn handle_help() {
     quit        Terminates the program
     help        Prints this help message 
 
-    listen      Streams information broadcasted by the robot (still needs work).
+    listen [--json] [OPCODE..]
+                Streams information broadcasted by the robot, optionally only some
+                opcodes (e.g. 130 134), and as one json object per line.
                 Press [Enter] to stop the stream.
     
 
     free        Will unlock the wheels of the robot, to be able to freely move it around
     stop        Will tell the robot to stop whatever it is currently doing
+    pose        Prints the coordinates and heading (in degrees) of the robot
+    state       Prints the current state of the robot, e.g. Idle or Charging
+    battery     Prints the battery level, voltage and charging status
+    stats       Prints how many frames came from the robot, and which couldn't be decoded
+
+    save NAME [TAG..] [--heading]
+                        Saves robots current coordinates as a waypoint, optionally with tags,
+                        and with --heading also the direction it is facing
+    goto NAME           Will try to route to the waypoint. If it has a heading, waits for the
+                        robot to get there and turns to it
+    goto X Y            Will try to route to the coordinates (in mm)
+    forward MM [force]  Drives straight ahead, or backwards if negative. Refuses if the
+                        depth sensors see an obstacle in the way, unless forced
+    turn DEGREES        Turns on the spot, counter-clockwise if positive
+    move DX DY          Will try to route to the point DX, DY mm away, along the map axes
+    heightmap [FILE]    Prints what the depth sensors see around the robot, and writes it
+                        to FILE if given, as a .png or .pgm image
+    list                Lists all saved waypoints
+    rename OLD NEW      Renames a waypoint
+    delete NAME         Deletes a waypoint
+    mission FILE        Runs the mission (route of waypoints) in FILE, see config/mission.sample
+    run FILE            Runs the script of commands in FILE, with waits, loops and conditions,
+                        see config/script.sample
+
+    record FILE         Records all traffic with the robot to FILE, for replaying with the replay tool
+    record stop         Stops recording
+
+Any command but quit can also be given on the command line, e.g. `console goto 100 200`,
+after these flags:
+
+    --config PATH       Config file to use instead of config/config
+    --robot HOST:PORT   Robot to connect to instead of the one in the config file
+    "); 
+}
 
-    save [a|b]  Saves robots current coordinates as location A or B
-    goto [a|b]  Will try to route to location A or B respectively
+/* Prints the counts of frames received, unknown opcodes and errors
+ */
+fn print_protocol_stats(stats: &ProtocolStats) {
+    println!("Frames received: {}, errors: {}, bytes skipped: {}", stats.frames, stats.errors, stats.skipped_bytes);
 
-    "); 
+    if !stats.unknown_opcodes.is_empty() {
+        let counts: Vec<String> = stats.unknown_opcodes.iter().map(|(opcode, count)| format!("{} ({}x)", opcode, count)).collect();
+        println!("Unknown opcodes: {}", counts.join(", "));
+    }
+
+    if let Some(ref error) = stats.last_error {
+        println!("Last error: {}", error);
+    }
 }
 
-/* Handler for the 'listen' command. Will spawn a thread which will read and parse
- * data from the TCPStream the robot is broadcasting
+/* Drives straight, after checking the last height map for obstacles in the way of the
+ * robot's body unless forced. Robots which send no height map aren't checked
  */
-fn handle_listen(robot:&mut Robot) { 
-    // Check connection
-    let robo_addr = robot.config.robot_address.to_owned() + ":" + &robot.config.robot_port;
-    let mut stream = match TcpStream::connect(robo_addr.as_str()) {
+fn handle_forward(robot: &mut Robot, distance: i32, force: bool) -> Result<(), Failure> {
+    if !force {
+        match robot.obstacle_ahead(distance) {
+            Ok(Some(gap)) => {
+                return Err(Failure::Failed(format!("Obstacle in the way after {:.0}mm, use 'forward {} force' to drive anyway",
+                                                   gap, distance)))
+            },
+            Ok(None) => {},
+            Err(e) => println!("Unable to check for obstacles: {}", e),
+        }
+    }
+
+    robot.forward(distance).map_err(send_failed)
+}
+
+/* Prints the last height map, and writes it to a .png or .pgm file if given one
+ */
+fn handle_height_map(robot: &mut Robot, image_path: Option<&str>) -> Result<(), Failure> {
+    if let Some(path) = image_path {
+        if !path.ends_with(".png") && !path.ends_with(".pgm") {
+            return Err(Failure::Usage(String::from("Height maps can be written to .png or .pgm files")));
+        }
+    }
+
+    let map = match robot.get_height_map() {
+        Ok(m) => m,
+        Err(e) => { return Err(Failure::Robot(e, String::from("No height map received from robot"))) }
+    };
+
+    // Only drawn if the robot has sent its footprint
+    let footprint = robot.get_robot_info().ok();
+
+    println!("{}x{} cells of {}mm at x={} y={} angle={:.1}, ahead of the robot (R) to the right",
+             map.xsamps, map.ysamps, map.unit_size, map.xorig, map.yorig, map.angle.degrees());
+    print!("{}", map.to_ascii(footprint.as_ref()));
+    println!("'r' robot  '.' floor  '-' threshold  'v' small drop  'V' big drop  'o' small item  'O' big item  '^' low ceiling  '#' wall");
+
+    if let Some(path) = image_path {
+        let image = if path.ends_with(".png") { map.to_png(HEIGHT_MAP_SCALE) } else { map.to_pgm(HEIGHT_MAP_SCALE) };
+
+        match File::create(path).and_then(|mut file| file.write_all(&image)) {
+            Ok(_) => println!("Written to {}", path),
+            Err(e) => { return Err(Failure::Config(format!("Unable to write image file {}: {}", path, e))) }
+        }
+    }
+
+    Ok(())
+}
+
+fn handle_list(robot: &Robot) {
+    if robot.config.waypoints.is_empty() {
+        println!("No waypoints saved");
+    }
+
+    for (name, waypoint) in &robot.config.waypoints {
+        let heading = match waypoint.heading {
+            Some(h) => format!("{:.1}", h),
+            None => String::from("-"),
+        };
+
+        println!("{:16} x={:<7} y={:<7} heading={:<6} {}", name, waypoint.x, waypoint.y, heading, waypoint.tags.join(" "));
+    }
+}
+
+fn handle_mission(robot: &mut Robot, mission_path: &str) -> Result<(), Failure> {
+    let mission = match Mission::from_file(mission_path) {
+        Ok(m) => m,
+        Err(e) => { return Err(Failure::Config(e.to_string())) }
+    };
+
+    let result = robot.run_mission(&mission, |event| {
+        match *event {
+            MissionEvent::StepStarted { lap, step, ref waypoint } => println!("[lap {} step {}] Going to {}", lap, step + 1, waypoint),
+            MissionEvent::Arrived { lap, step, ref waypoint } => println!("[lap {} step {}] Arrived at {}", lap, step + 1, waypoint),
+            MissionEvent::StepFailed { lap, step, ref waypoint, outcome } => println!("[lap {} step {}] Failed to reach {}: {:?}", lap, step + 1, waypoint, outcome),
+            MissionEvent::Completed { laps } => println!("Mission completed ({} laps)", laps),
+        }
+    });
+
+    match result {
+        Ok(GotoOutcome::Arrived) => Ok(()),
+        Ok(outcome) => Err(Failure::Failed(format!("Mission failed: {:?}", outcome))),
+        Err(ref e) if e.err_type == RobotErrorType::Config => Err(Failure::Config(e.to_string())),
+        Err(e) => Err(Failure::Robot(e, String::from("Mission aborted"))),
+    }
+}
+
+fn handle_script(robot: &mut Robot, script_path: &str) -> Result<(), Failure> {
+    let script = match Script::from_file(script_path) {
         Ok(s) => s,
-        Err(_) => panic!("Failed to connect to Robot")
+        Err(ref e) if e.line == 0 => { return Err(Failure::Config(format!("{}: {}", script_path, e.message))) },
+        Err(e) => { return Err(Failure::Config(format!("Error in script {} at line {}: {}", script_path, e.line, e.message))) }
     };
 
-    println!("Listening to robot.. Press [Enter] to stop listening\n");
+    let result = robot.run_script(&script, |robot, args| {
+        let args: Vec<&str> = args.iter().map(|a| a.as_str()).collect();
+        run_command(robot, &args).map_err(|f| f.message())
+    }, |step| {
+        match step.result {
+            Ok(ref note) => println!("[line {}] {}: {}", step.line, step.text, note),
+            Err(ref e) => println!("[line {}] {}: FAILED: {}", step.line, step.text, e),
+        }
+    });
 
-    let (tx, rx) = mpsc::channel();
+    match result {
+        Ok(_) => { println!("Script completed"); Ok(()) },
+        Err(e) => Err(Failure::Failed(format!("Script failed at line {}: {}", e.line, e.message))),
+    }
+}
 
-    thread::spawn(move || {
-        loop {
-            // Read from robot
-            let mut cmd_buf = [0; 3];
+fn write_config(robot: &mut Robot) -> Result<(), Failure> {
+    match robot.config.write(&robot.config_path) {
+        Ok(_) => Ok(()),
+        Err(e) => Err(Failure::Config(e.to_string())),
+    }
+}
 
-            stream.read_exact(&mut cmd_buf).unwrap();
+fn format_battery(battery: &BatteryStatus) -> String {
+    format!("{}% (charging={} finished={} voltage={}.{:03}V)",
+        battery.percentage, battery.charging, battery.finished,
+        battery.voltage / 1000, battery.voltage % 1000)
+}
 
-            let cmd = cmd_buf[0];
-            let len: i32 = ((cmd_buf[1] as i32) << 8) | (cmd_buf[2] as i32);
+/* Parses the arguments of `listen`: --json, and opcodes to filter on
+ */
+fn parse_listen_options<S: AsRef<str>>(args: &[S]) -> Result<ListenOptions, String> {
+    let mut options = ListenOptions { json: false, opcodes: Vec::new() };
+
+    for arg in args {
+        match arg.as_ref() {
+            "--json" => options.json = true,
+            "" => {},
+            opcode => match opcode.parse() {
+                Ok(o) => options.opcodes.push(o),
+                Err(_) => { return Err(format!("Not an opcode: {}", opcode)) }
+            },
+        }
+    }
 
-            let mut buf: Vec<u8> = vec![0;len as usize];
-            stream.read_exact(&mut buf[..]).unwrap();
+    Ok(options)
+}
 
-            match cmd {
-                130 => { // Position
-                    let mut buf_angle = &buf[0..2];
-                    let mut buf_x = &buf[2..6];
-                    let mut buf_y = &buf[6..10];
+fn print_message<W: Write>(out: &mut W, message: &Message, options: &ListenOptions) -> io::Result<()> {
+    if !options.opcodes.is_empty() && !options.opcodes.contains(&message.opcode()) {
+        return Ok(());
+    }
 
-                    let angle = buf_angle.read_i16::<BigEndian>().unwrap();
-                    let x = buf_x.read_i32::<BigEndian>().unwrap();
-                    let y = buf_y.read_i32::<BigEndian>().unwrap();
+    if options.json {
+        return match serde_json::to_string(&MessageRecord::new(message.clone())) {
+            Ok(json) => writeln!(out, "{}", json),
+            Err(_) => Ok(()),
+        };
+    }
 
-                    let real_angle = (angle as f32) / 65536.0 * 360.0;
+    match *message {
+        Message::Position(pose) => {
+            writeln!(out, "[130] Location: x={}, y={}, angle={}", pose.x, pose.y, pose.angle.degrees())
+        },
+        Message::Battery(battery) => {
+            writeln!(out, "[134] Battery {}", format_battery(&battery))
+        },
+        Message::TofHeightMap(ref map) => {
+            let obstacles = map.cells.iter().filter(|cell| cell.is_obstacle()).count();
+            writeln!(out, "[138] Height map: {}x{} cells of {}mm at x={}, y={}, angle={}, {} obstacle cells",
+                     map.xsamps, map.ysamps, map.unit_size, map.xorig, map.yorig, map.angle.degrees(), obstacles)
+        },
+        Message::State(state) => {
+            writeln!(out, "[139] State: {:?}", state)
+        },
+        Message::RobotInfo(info) => {
+            writeln!(out, "[140] Robot info: {}x{}mm, middle at ({}, {}) from its position", info.xs, info.ys, info.xoffs, info.yoffs)
+        },
+        Message::Unknown { opcode, ref payload } => {
+            writeln!(out, "[{}:{}] Unhandled command", opcode, payload.len())
+        },
+    }
+}
 
-                    println!("[130:{}] Location: x={}, y={}, angle={}", len, x, y, real_angle);
-                },
-                134 => { // Battery
-                    let mut charging:bool = false;
-                    let mut finished:bool = false;
+/* Handler for the 'listen' command. Will spawn a thread which will print the messages
+ * the robot is broadcasting, as decoded by the robot's reader thread
+ */
+fn handle_listen(robot: &mut Robot, args: &[&str]) {
+    let options = match parse_listen_options(args) {
+        Ok(o) => o,
+        Err(e) => {
+            println!("{}", e);
+            return;
+        }
+    };
 
-                    let buf_charging = buf[0]&1;
-                    let buf_finished = buf[0]&2; // Finished charging
-                    let buf_voltage = ((buf[1] as i32) << 8) | (buf[2] as i32)/1000;
-                    let percentage = buf[3];
+    let messages = robot.subscribe();
 
-                    if buf_charging == 1 {
-                        charging = true;
-                    }
+    println!("Listening to robot.. Press [Enter] to stop listening\n");
 
-                    if buf_finished == 1 {
-                        finished = true;
-                    }
+    let (tx, rx) = mpsc::channel();
 
-                    println!("[134:{}] Battery {}% (charging={} finished={} voltage={}", len, percentage, charging, finished, buf_voltage);
-                },
-                138 => { // 3D TOF HMAP
-                    println!("[{}:{}] 3D TOF HMAP", cmd, len);
-                },
-                139 => { // State
-                    let state_num = buf[0];
-                    //let state = RobotState::from(buf[0] as i8);
-                    println!("[139:{}] {}", len, state_num); 
-                },
-                140 => { // No idea what this is.. Possible the size of the robot?
-                    let mut buf_xs = &buf[0..2];
-                    let mut buf_ys = &buf[2..4];
-                    let mut buf_xoffs = &buf[4..6];
-                    let mut buf_yoffs = &buf[6..8];
-
-                    let xs = buf_xs.read_i16::<BigEndian>().unwrap();
-                    let ys = buf_ys.read_i16::<BigEndian>().unwrap();
-                    let xoffs = buf_xoffs.read_i16::<BigEndian>().unwrap();
-                    let yoffs = buf_yoffs.read_i16::<BigEndian>().unwrap();
-
-                    println!("[140:{}] Something fetched: ({}, {}, {}, {})", len, xs, ys, xoffs, yoffs);
-                },
-                _ => {
-                    println!("[{}:{}] Unhandled command", cmd, len);
+    thread::spawn(move || {
+        loop {
+            match messages.recv() {
+                Ok(message) => { let _ = print_message(&mut io::stdout(), &message, &options); },
+                Err(_) => {
+                    println!("Lost connection to robot");
+                    break;
                 }
             }
 
             match rx.try_recv() {
-                Ok(_) | Err(TryRecvError::Disconnected) => {
-                    stream.shutdown(Shutdown::Both).unwrap();
-                    break;
-                }
+                Ok(_) | Err(TryRecvError::Disconnected) => break,
                 Err(TryRecvError::Empty) => {}
             }
         }
@@ -239,3 +861,87 @@ fn handle_listen(robot:&mut Robot) {
     let _ = tx.send(());
 }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pulurobot_robot::pulurobot::RobotErrorHandler;
+
+    fn args(line: &str) -> Vec<String> {
+        line.split_whitespace().map(String::from).collect()
+    }
+
+    fn options(line: &str) -> Options {
+        match parse_options(&args(line)) {
+            Ok(o) => o,
+            Err(f) => panic!("{}", f.message()),
+        }
+    }
+
+    #[test]
+    fn parses_flags_before_the_command() {
+        let parsed = options("--config /etc/robot/config --robot 10.0.0.5:22222 goto 100 200");
+        assert_eq!(parsed.config_path, "/etc/robot/config");
+        assert_eq!(parsed.robot, Some((String::from("10.0.0.5"), String::from("22222"))));
+        assert_eq!(parsed.command, args("goto 100 200"));
+
+        // Flags after the command belong to it
+        let parsed = options("listen --json --robot 130");
+        assert_eq!((parsed.config_path.as_str(), parsed.robot), ("config/config", None));
+        assert_eq!(parsed.command, args("listen --json --robot 130"));
+
+        assert!(options("").command.is_empty());
+        assert_eq!(parse_options(&args("--config")).err().map(|f| f.exit_code()), Some(2));
+        assert_eq!(parse_options(&args("stop --robot")).ok().map(|o| o.command), Some(args("stop --robot")));
+    }
+
+    #[test]
+    fn splits_robot_addresses() {
+        assert_eq!(options("--robot [::1]:22222 stop").robot, Some((String::from("[::1]"), String::from("22222"))));
+
+        for addr in &["robot", "robot:", ":22222", "robot:port", "robot:99999"] {
+            let failure = parse_options(&args(&format!("--robot {} stop", addr))).err().unwrap();
+            assert_eq!((failure.exit_code(), failure.message()), (2, format!("Expected HOST:PORT, got: {}", addr)));
+        }
+    }
+
+    #[test]
+    fn maps_failures_to_exit_codes() {
+        let refused = RobotError::new(RobotErrorType::Connection).with_address("10.0.0.5:22222");
+        let not_arrived = RobotError::new(RobotErrorType::NotArrived);
+
+        assert_eq!(Failure::Failed(String::new()).exit_code(), 1);
+        assert_eq!(Failure::Usage(String::new()).exit_code(), 2);
+        assert_eq!(Failure::Config(String::new()).exit_code(), 3);
+        assert_eq!(Failure::Robot(refused, String::new()).exit_code(), 10);
+        assert_eq!(Failure::Robot(not_arrived, String::new()).exit_code(), 1);
+        assert_eq!(send_failed(RobotError::new(RobotErrorType::Timeout)).exit_code(), 14);
+
+        // Wrong commands are caught before connecting, a missing config file when connecting
+        assert_eq!(run_command_line(&options("--config /nonexistent fly")), 2);
+        assert_eq!(run_command_line(&options("--config /nonexistent listen 1000")), 2);
+        assert_eq!(run_command_line(&options("--config /nonexistent stop")), 3);
+    }
+
+    #[test]
+    fn explains_failures() {
+        let refused = Failure::Robot(RobotError::new(RobotErrorType::Connection).with_address("10.0.0.5:22222"), String::new());
+        assert!(refused.report().starts_with("Unable to connect to 10.0.0.5:22222\nCheck that the robot is switched on"));
+
+        let file = RobotError::new(RobotErrorType::Write).with_path("/tmp/capture.jsonl");
+        assert_eq!(Failure::Robot(file, String::from("Unable to record")).report(),
+                   "Unable to record: Unable to write to /tmp/capture.jsonl");
+    }
+
+    #[test]
+    fn parses_listen_options() {
+        let parsed = parse_listen_options(&["--json", "130", "134"]).ok().unwrap();
+        assert!(parsed.json);
+        assert_eq!(parsed.opcodes, vec![130, 134]);
+
+        let parsed = parse_listen_options::<&str>(&[]).ok().unwrap();
+        assert!(!parsed.json && parsed.opcodes.is_empty());
+
+        assert_eq!(parse_listen_options(&["position"]).err(), Some(String::from("Not an opcode: position")));
+        assert_eq!(parse_listen_options(&["256"]).err(), Some(String::from("Not an opcode: 256")));
+    }
+}
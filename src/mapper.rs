@@ -0,0 +1,88 @@
+/*
+ * Builds a map of the site from the robot's height maps and positions, saved as json
+ */
+
+extern crate pulurobot_robot;
+
+use std::env;
+use std::process;
+use std::time::{Duration, Instant};
+
+use pulurobot_robot::pulurobot::{Robot, PuluRobot, OccupancyGrid, Mapper, ConfigErrorType, RobotError};
+
+/* How often the map is written, at most */
+const SAVE_INTERVAL_SECS: u64 = 5;
+
+/* Map cell size in mm, unless given */
+const DEFAULT_CELL_SIZE: i32 = 50;
+
+fn main() {
+    let args: Vec<String> = env::args().collect();
+
+    if args.len() < 2 {
+        eprintln!("Usage: mapper FILE [CELL_SIZE]");
+        process::exit(2);
+    }
+
+    let map_path = &args[1];
+
+    let cell_size = match args.get(2).map(|s| s.parse()) {
+        Some(Ok(s)) if s > 0 => s,
+        Some(_) => {
+            eprintln!("Usage: mapper FILE [CELL_SIZE]");
+            process::exit(2);
+        },
+        None => DEFAULT_CELL_SIZE,
+    };
+
+    // Carry on with an existing map
+    let grid = match OccupancyGrid::load(map_path) {
+        Ok(grid) => {
+            println!("Extending {}, with cells of {}mm", map_path, grid.cell_size);
+            grid
+        },
+        Err(ref e) if e.err_type == ConfigErrorType::FileNotFound => OccupancyGrid::new(cell_size),
+        // Rather than overwriting it
+        Err(e) => {
+            let e = RobotError::from(e);
+            eprintln!("{}", e);
+            process::exit(e.exit_code());
+        }
+    };
+
+    let mut robot = match Robot::from_config("config/config") {
+        Ok(s) => s,
+        Err(e) => {
+            eprintln!("{}", e);
+            process::exit(e.exit_code());
+        }
+    };
+
+    let messages = robot.subscribe();
+    let mut mapper = Mapper::new(grid);
+    let mut changed = false;
+    let mut last_save = Instant::now();
+
+    println!("Mapping, saving to {} every {} seconds. Press Ctrl-C to stop", map_path, SAVE_INTERVAL_SECS);
+
+    loop {
+        // Keeps going across reconnects, the channel only closes with the robot
+        if let Ok(message) = messages.recv_timeout(Duration::new(1, 0)) {
+            changed |= mapper.update(&message);
+        }
+
+        if changed && last_save.elapsed() >= Duration::new(SAVE_INTERVAL_SECS, 0) {
+            if let Err(e) = mapper.grid.save(map_path) {
+                let e = RobotError::from(e);
+                eprintln!("{}", e);
+                process::exit(e.exit_code());
+            }
+
+            let (cells, occupied) = mapper.grid.count();
+            println!("Saved {} cells, {} of them occupied", cells, occupied);
+
+            changed = false;
+            last_save = Instant::now();
+        }
+    }
+}
@@ -0,0 +1,206 @@
+
+/* Detecting whether the robot made it to where it was sent */
+
+use super::*;
+use std::sync::mpsc::RecvTimeoutError;
+use std::fmt;
+
+/* Distance in mm the robot must move before it counts as making progress */
+const PROGRESS_MM: f64 = 50.0;
+
+/* How long the robot may take to start moving after a goto */
+const START_SECS: u64 = 5;
+
+/* How long the robot may be busy without making progress before it counts as blocked */
+const STALL_SECS: u64 = 15;
+
+/* How often to re-check the timeouts while no messages arrive */
+const POLL_MS: u64 = 100;
+
+/* Timeouts used to tell a robot that stopped or got blocked from one still driving */
+struct ArrivalLimits {
+    start: Duration,
+    stall: Duration,
+}
+
+impl fmt::Display for GotoOutcome {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            GotoOutcome::Arrived => write!(f, "arrived"),
+            GotoOutcome::TimedOut => write!(f, "timed out"),
+            GotoOutcome::Stopped => write!(f, "stopped"),
+            GotoOutcome::Blocked => write!(f, "blocked"),
+        }
+    }
+}
+
+/* Follows position and state messages after a goto to (x, y) was sent, until the robot
+ * has arrived within `tolerance` mm, has stopped or got blocked, or `timeout` runs out.
+ * `telemetry` holds the values from before the goto was sent. The caller adds the
+ * robot's address to the error.
+ */
+pub fn wait_for_arrival(rx: &Receiver<Message>, telemetry: &Telemetry, x: i32, y: i32, tolerance: i32,
+                        timeout: Duration) -> Result<GotoOutcome, RobotError> {
+    let limits = ArrivalLimits {
+        start: Duration::new(START_SECS, 0),
+        stall: Duration::new(STALL_SECS, 0),
+    };
+
+    track_arrival(rx, telemetry, x, y, tolerance, timeout, &limits)
+}
+
+fn track_arrival(rx: &Receiver<Message>, telemetry: &Telemetry, x: i32, y: i32, tolerance: i32,
+                 timeout: Duration, limits: &ArrivalLimits) -> Result<GotoOutcome, RobotError> {
+    let start = Instant::now();
+    let mut pose = telemetry.pose;
+    let mut state = telemetry.state;
+    let mut started = false;
+
+    // Where and when the robot was last seen making progress
+    let mut progress_pose = pose;
+    let mut progress_at = start;
+
+    loop {
+        let now = Instant::now();
+        let near = pose.is_some_and(|p| p.distance_to(x, y) <= tolerance as f64);
+        let idle = state == Some(RobotState::Idle);
+
+        if idle && near {
+            return Ok(GotoOutcome::Arrived);
+        }
+
+        if idle && (started || now - start >= limits.start) {
+            return Ok(GotoOutcome::Stopped);
+        }
+
+        if !idle && now - progress_at >= limits.stall {
+            return Ok(GotoOutcome::Blocked);
+        }
+
+        if now - start >= timeout {
+            return Ok(GotoOutcome::TimedOut);
+        }
+
+        match rx.recv_timeout(Duration::from_millis(POLL_MS)) {
+            Ok(Message::Position(p)) => {
+                let moved = match progress_pose {
+                    Some(last) => last.distance_to(p.x, p.y) >= PROGRESS_MM,
+                    None => true,
+                };
+
+                if moved {
+                    progress_pose = Some(p);
+                    progress_at = Instant::now();
+                }
+
+                pose = Some(p);
+            },
+            Ok(Message::State(s)) => {
+                if s != RobotState::Idle {
+                    started = true;
+                }
+
+                state = Some(s);
+            },
+            Ok(_) | Err(RecvTimeoutError::Timeout) => {},
+            Err(RecvTimeoutError::Disconnected) => {
+                return Err( RobotError::new(RobotErrorType::Read).with_opcode(OPCODE_POSITION)
+                    .with_source("the robot was disconnected") )
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::mpsc;
+
+    fn pose(x: i32, y: i32) -> Message {
+        Message::Position(RobotPose { x, y, angle: Angle::default() })
+    }
+
+    fn idle_at(x: i32, y: i32) -> Telemetry {
+        Telemetry {
+            pose: Some(RobotPose { x, y, angle: Angle::default() }),
+            state: Some(RobotState::Idle),
+            ..Telemetry::default()
+        }
+    }
+
+    fn track(messages: Vec<Message>, limits: &ArrivalLimits) -> GotoOutcome {
+        let (tx, rx) = mpsc::channel();
+
+        for message in messages {
+            tx.send(message).unwrap();
+        }
+
+        match track_arrival(&rx, &idle_at(0, 0), 1000, 0, 100, Duration::from_millis(500), limits) {
+            Ok(outcome) => outcome,
+            Err(_) => panic!("Message channel closed"),
+        }
+    }
+
+    const LIMITS: ArrivalLimits = ArrivalLimits {
+        start: Duration::from_millis(200),
+        stall: Duration::from_millis(200),
+    };
+
+    #[test]
+    fn detects_arrival() {
+        let messages = vec![
+            Message::State(RobotState::Fwd),
+            pose(500, 0),
+            pose(950, 20),
+            Message::State(RobotState::Idle),
+        ];
+
+        assert_eq!(track(messages, &LIMITS), GotoOutcome::Arrived);
+    }
+
+    #[test]
+    fn detects_stopping_short() {
+        let messages = vec![
+            Message::State(RobotState::Fwd),
+            pose(500, 0),
+            Message::State(RobotState::Idle),
+        ];
+
+        assert_eq!(track(messages, &LIMITS), GotoOutcome::Stopped);
+    }
+
+    #[test]
+    fn detects_never_starting() {
+        assert_eq!(track(vec![], &LIMITS), GotoOutcome::Stopped);
+    }
+
+    #[test]
+    fn detects_being_blocked() {
+        let messages = vec![
+            Message::State(RobotState::Think),
+            pose(10, 0),
+        ];
+
+        assert_eq!(track(messages, &LIMITS), GotoOutcome::Blocked);
+    }
+
+    #[test]
+    fn times_out() {
+        let limits = ArrivalLimits {
+            start: Duration::from_millis(200),
+            stall: Duration::from_secs(10),
+        };
+
+        assert_eq!(track(vec![Message::State(RobotState::Fwd)], &limits), GotoOutcome::TimedOut);
+    }
+
+    #[test]
+    fn fails_when_the_robot_goes_away() {
+        let (tx, rx) = mpsc::channel::<Message>();
+        drop(tx);
+
+        let error = track_arrival(&rx, &idle_at(0, 0), 1000, 0, 100, Duration::from_millis(500), &LIMITS)
+            .err().unwrap().with_address("10.0.0.5:22222");
+        assert_eq!(error.to_string(), "Unable to read from 10.0.0.5:22222 (opcode 130): the robot was disconnected");
+    }
+}
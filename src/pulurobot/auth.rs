@@ -0,0 +1,115 @@
+
+/* Keys and signatures authenticating the robot and the fleet server to each other */
+
+use super::*;
+use std::io;
+use rand::{OsRng, Rng};
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use super::capture::hex;
+
+type HmacSha256 = Hmac<Sha256>;
+
+/* Random bytes in a generated key, or session id */
+const KEY_LEN: usize = 32;
+const SESSION_LEN: usize = 16;
+
+fn random_hex(len: usize) -> io::Result<String> {
+    let mut bytes = vec![0; len];
+    OsRng::new()?.fill_bytes(&mut bytes);
+    Ok(hex::encode(&bytes))
+}
+
+/* A new private key for a robot, as hex. Shared by the robot and the fleet server
+ */
+pub fn generate_key() -> io::Result<String> {
+    random_hex(KEY_LEN)
+}
+
+/* A new session id, picked by the robot every time it connects to the server, so
+ * commands signed for an earlier connection are rejected
+ */
+pub fn generate_session() -> io::Result<String> {
+    random_hex(SESSION_LEN)
+}
+
+/* The bytes of a private key as written in the config file, in hex
+ */
+pub fn decode_key(key: &str) -> Result<Vec<u8>, RobotError> {
+    if key.is_empty() {
+        return Err( RobotError::new(RobotErrorType::Authentication).with_source("no private_key in the config file") );
+    }
+
+    match hex::decode(key) {
+        Ok(bytes) => Ok(bytes),
+        Err(e) => Err( RobotError::new(RobotErrorType::Authentication).with_source(format!("private_key is not hex: {}", e)) )
+    }
+}
+
+fn mac(key: &[u8], message: &str) -> HmacSha256 {
+    let mut mac = HmacSha256::new_from_slice(key).expect("HMAC takes keys of any length");
+    mac.update(message.as_bytes());
+    mac
+}
+
+/* HMAC-SHA256 of `message` with `key`, as hex
+ */
+pub fn sign(key: &[u8], message: &str) -> String {
+    hex::encode(&mac(key, message).finalize().into_bytes())
+}
+
+/* Checks a signature made by sign, taking the same time whatever the mismatch
+ */
+pub fn verify(key: &[u8], message: &str, signature: &str) -> bool {
+    match hex::decode(signature) {
+        Ok(signature) => mac(key, message).verify_slice(&signature).is_ok(),
+        Err(_) => false,
+    }
+}
+
+/* What the robot signs to answer the server's challenge
+ */
+pub fn auth_message(nonce: &str, session: &str) -> String {
+    format!("auth:{}:{}", nonce, session)
+}
+
+/* What the server signs to send a command
+ */
+pub fn command_message(session: &str, seq: u64, payload: &str) -> String {
+    format!("command:{}:{}:{}", session, seq, payload)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn signs_like_hmac_sha256() {
+        // RFC 4231 test cases 2 and 6, the latter with a key longer than a block
+        let jefe = decode_key("4a656665").unwrap();
+        let signature = "5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843";
+
+        assert_eq!(sign(&jefe, "what do ya want for nothing?"), signature);
+        assert_eq!(sign(&[0xaa; 131], "Test Using Larger Than Block-Size Key - Hash Key First"),
+                   "60e431591ee0b67f0d8a26aacbf5b77f8e0bc6213728c5140546040f0ee37f54");
+        assert!(verify(&jefe, "what do ya want for nothing?", signature));
+        assert!(!verify(&jefe, "what do ya want for nothing!", signature));
+        assert!(!verify(&jefe, "what do ya want for nothing?", ""));
+        assert!(!verify(&jefe, "what do ya want for nothing?", "not hex"));
+    }
+
+    #[test]
+    fn decodes_keys_from_hex() {
+        assert_eq!(decode_key("00ff10").ok(), Some(vec![0x00, 0xff, 0x10]));
+        assert_eq!(decode_key("").err().map(|e| e.err_type), Some(RobotErrorType::Authentication));
+        assert!(decode_key("not hex!").is_err());
+    }
+
+    #[test]
+    fn generates_distinct_keys() {
+        let key = generate_key().unwrap();
+
+        assert_eq!(key.len(), KEY_LEN * 2);
+        assert_ne!(key, generate_key().unwrap());
+    }
+}
@@ -0,0 +1,438 @@
+
+/* Bridge between the robot and a fleet server, speaking json over a WebSocket */
+
+use super::*;
+use super::auth::generate_session;
+use std::sync::atomic::Ordering;
+use std::thread;
+use serde_json;
+use url::Url;
+use ws;
+
+impl ServerEvent {
+    /* The event to publish for a message from the robot, if the server cares about it
+     */
+    pub fn from_message(message: &Message) -> Option<ServerEvent> {
+        match *message {
+            Message::Position(pose) => Some(ServerEvent::Pose { x: pose.x, y: pose.y, angle: pose.angle.degrees() }),
+            Message::Battery(battery) => Some(ServerEvent::Battery {
+                charging: battery.charging,
+                finished: battery.finished,
+                voltage: battery.voltage,
+                percentage: battery.percentage,
+            }),
+            Message::State(state) => Some(ServerEvent::State { state: format!("{:?}", state) }),
+            Message::RobotInfo(info) => Some(ServerEvent::RobotInfo {
+                xs: info.xs,
+                ys: info.ys,
+                xoffs: info.xoffs,
+                yoffs: info.yoffs,
+            }),
+            _ => None,
+        }
+    }
+}
+
+/* Carries out a command from the server. Commands return as soon as they have been
+ * sent, so goto_waypoint doesn't wait to turn to the waypoint's heading. Errors are
+ * passed on to the server as text.
+ */
+fn execute(robot: &mut Robot, command: &ServerCommand) -> Result<(), String> {
+    let result = match *command {
+        ServerCommand::Goto { x, y } => robot.goto(x, y),
+        ServerCommand::GotoWaypoint { ref name } => {
+            let waypoint = match robot.config.get_waypoint(name) {
+                Ok(w) => w,
+                Err(e) => { return Err(e.to_string()) }
+            };
+
+            robot.goto(waypoint.x, waypoint.y)
+        },
+        ServerCommand::Stop => robot.stop(),
+        ServerCommand::Free => robot.free(),
+        ServerCommand::Localize => robot.localize(),
+        ServerCommand::SaveWaypoint { ref name, ref tags, heading } => robot.save_waypoint(name, tags.clone(), heading),
+    };
+
+    result.map_err(|e| e.to_string())
+}
+
+/* Sends an event to the server, if connected
+ */
+fn publish(server: &Mutex<Option<ws::Sender>>, event: &ServerEvent) {
+    if let Some(ref out) = *server.lock().unwrap() {
+        if let Ok(json) = serde_json::to_string(event) {
+            let _ = out.send(json);
+        }
+    }
+}
+
+/* Handles a single connection to the server */
+struct ServerHandler {
+    out: ws::Sender,
+    server: Arc<Mutex<Option<ws::Sender>>>,
+    robot: Arc<Mutex<Robot>>,
+    opened: Arc<AtomicBool>,
+    name: String,
+    private_key: Vec<u8>,
+    /* Picked when answering a challenge, None until then */
+    session: Option<String>,
+    /* Sequence number of the last command accepted in this session */
+    last_seq: u64,
+}
+
+impl ServerHandler {
+    fn send(&self, event: &ServerEvent) {
+        if let Ok(json) = serde_json::to_string(event) {
+            let _ = self.out.send(json);
+        }
+    }
+
+    /* Proves to the server that we hold the private key, and starts a new session.
+     * Telemetry is only published from then on.
+     */
+    fn answer_challenge(&mut self, nonce: &str) {
+        let session = match generate_session() {
+            Ok(s) => s,
+            Err(_) => {
+                let _ = self.out.close(ws::CloseCode::Error);
+                return;
+            }
+        };
+
+        let signature = sign(&self.private_key, &auth_message(nonce, &session));
+
+        self.send(&ServerEvent::Auth { robot: self.name.clone(), session: session.clone(), signature });
+
+        self.session = Some(session);
+        self.last_seq = 0;
+        *self.server.lock().unwrap() = Some(self.out.clone());
+    }
+
+    /* Accepts a command only if it is signed for this session, and newer than the last
+     */
+    fn check_command(&mut self, seq: u64, payload: &str, signature: &str) -> Result<ServerCommand, &'static str> {
+        let session = match self.session {
+            Some(ref s) => s,
+            None => { return Err("not authenticated") }
+        };
+
+        if !verify(&self.private_key, &command_message(session, seq, payload), signature) {
+            return Err("bad signature");
+        }
+
+        if seq <= self.last_seq {
+            return Err("replayed command");
+        }
+
+        self.last_seq = seq;
+
+        match serde_json::from_str(payload) {
+            Ok(command) => Ok(command),
+            Err(_) => Err("invalid command"),
+        }
+    }
+}
+
+impl ws::Handler for ServerHandler {
+    fn on_open(&mut self, _: ws::Handshake) -> ws::Result<()> {
+        self.opened.store(true, Ordering::SeqCst);
+        Ok(())
+    }
+
+    fn on_message(&mut self, msg: ws::Message) -> ws::Result<()> {
+        let request = msg.as_text().ok().and_then(|text| serde_json::from_str::<ServerRequest>(text).ok());
+
+        let result = match request {
+            Some(ServerRequest::Challenge { nonce }) => {
+                self.answer_challenge(&nonce);
+                return Ok(());
+            },
+            Some(ServerRequest::Command { seq, payload, signature }) => {
+                match self.check_command(seq, &payload, &signature) {
+                    Ok(command) => execute(&mut self.robot.lock().unwrap(), &command),
+                    Err(e) => Err(String::from(e)),
+                }
+            },
+            None => Err(String::from("invalid request")),
+        };
+
+        self.send(&ServerEvent::Reply { ok: result.is_ok(), error: result.err() });
+
+        Ok(())
+    }
+
+    fn on_close(&mut self, _: ws::CloseCode, _: &str) {
+        *self.server.lock().unwrap() = None;
+    }
+
+    // Failed connections are retried by run_bridge, so there is nothing to report
+    fn on_error(&mut self, _: ws::Error) {}
+}
+
+/* Rejects urls that ws::connect would never get through to, by the same rules
+ */
+fn check_url(url: &str) -> Result<(), RobotError> {
+    let invalid = |reason: String| RobotError::new(RobotErrorType::Connection).with_address(url).with_source(reason);
+
+    let parsed = match Url::parse(url) {
+        Ok(u) => u,
+        Err(e) => { return Err(invalid(format!("invalid url: {}", e))) }
+    };
+
+    match (parsed.scheme(), parsed.host_str()) {
+        (_, None) => Err(invalid(String::from("no host in the url"))),
+        ("ws", _) => Ok(()),
+        ("wss", _) if cfg!(feature = "tls") => Ok(()),
+        ("wss", _) => Err(invalid(String::from("wss:// needs the bridge built with --features tls"))),
+        (scheme, _) => Err(invalid(format!("expected a ws:// or wss:// url, not {}://", scheme))),
+    }
+}
+
+/* Connects to the WebSocket server at `url`, e.g. "wss://fleet.example.com:8080", and
+ * once it has answered the server's challenge, publishes the robot's telemetry to it and
+ * carries out the signed commands it sends. Blocks, reconnecting with backoff whenever
+ * the server goes away or can't be reached, and only returns on errors that retrying
+ * won't fix, like an invalid url or a missing private key. wss:// urls need the tls
+ * feature, over ws:// the commands can't be forged, but anyone on the way can read
+ * everything sent.
+ */
+pub fn run_bridge(mut robot: Robot, url: &str) -> Result<(), RobotError> {
+    let private_key = decode_key(&robot.config.private_key).map_err(|e| e.with_address(url))?;
+
+    check_url(url)?;
+
+    let server: Arc<Mutex<Option<ws::Sender>>> = Arc::new(Mutex::new(None));
+
+    {
+        let messages = robot.subscribe();
+        let server = Arc::clone(&server);
+
+        thread::spawn(move || {
+            for message in messages {
+                if let Some(event) = ServerEvent::from_message(&message) {
+                    publish(&server, &event);
+                }
+            }
+        });
+    }
+
+    {
+        let link_events = robot.subscribe_link();
+        let server = Arc::clone(&server);
+
+        thread::spawn(move || {
+            for state in link_events {
+                match state {
+                    LinkState::Connected => publish(&server, &ServerEvent::Link { connected: true }),
+                    LinkState::Disconnected => publish(&server, &ServerEvent::Link { connected: false }),
+                    LinkState::Retrying { .. } => {},
+                }
+            }
+        });
+    }
+
+    let initial_backoff = Duration::from_millis(robot.config.reconnect.initial_backoff_ms);
+    let max_backoff = Duration::from_millis(robot.config.reconnect.max_backoff_ms);
+    let mut delay = initial_backoff;
+
+    let name = robot.config.name.clone();
+    let robot = Arc::new(Mutex::new(robot));
+
+    loop {
+        let opened = Arc::new(AtomicBool::new(false));
+
+        // Errors are retried after the backoff: the url was checked up front, so they come
+        // from the network, e.g. a name that doesn't resolve while the wifi is down
+        let _ = ws::connect(url, |out| ServerHandler {
+            out,
+            server: Arc::clone(&server),
+            robot: Arc::clone(&robot),
+            opened: Arc::clone(&opened),
+            name: name.clone(),
+            private_key: private_key.clone(),
+            session: None,
+            last_seq: 0,
+        });
+
+        *server.lock().unwrap() = None;
+
+        if opened.load(Ordering::SeqCst) {
+            delay = initial_backoff;
+        }
+
+        thread::sleep(delay);
+        delay = link::next_backoff(delay, max_backoff);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::mpsc;
+
+    const KEY: &str = "0123456789abcdef0123456789abcdef";
+
+    /* A fleet server which challenges the robot, then sends a goto command, the same
+     * command again, and one with a bad signature. Passes on everything it receives.
+     */
+    struct FleetServer {
+        out: ws::Sender,
+        tx: mpsc::Sender<String>,
+    }
+
+    impl FleetServer {
+        fn send_command(&self, session: &str, seq: u64, key: &[u8]) {
+            let payload = r#"{"command": "goto", "x": 0, "y": 500}"#;
+            let signature = sign(key, &command_message(session, seq, payload));
+            let request = ServerRequest::Command { seq, payload: String::from(payload), signature };
+
+            self.out.send(serde_json::to_string(&request).unwrap()).unwrap();
+        }
+    }
+
+    impl ws::Handler for FleetServer {
+        fn on_open(&mut self, _: ws::Handshake) -> ws::Result<()> {
+            let challenge = ServerRequest::Challenge { nonce: String::from("n0nce") };
+            self.out.send(serde_json::to_string(&challenge).unwrap())
+        }
+
+        fn on_message(&mut self, msg: ws::Message) -> ws::Result<()> {
+            let text = msg.into_text()?;
+            let event: serde_json::Value = serde_json::from_str(&text).unwrap();
+
+            if event["type"] == "auth" {
+                let session = event["session"].as_str().unwrap();
+                let signature = event["signature"].as_str().unwrap();
+
+                let key = decode_key(KEY).unwrap();
+
+                if verify(&key, &auth_message("n0nce", session), signature) {
+                    self.send_command(session, 1, &key);
+                    self.send_command(session, 1, &key);
+                    self.send_command(session, 2, &[0x42; 16]);
+                }
+            }
+
+            let _ = self.tx.send(text);
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn parses_commands() {
+        assert_eq!(serde_json::from_str::<ServerCommand>(r#"{"command": "goto", "x": 100, "y": -200}"#).ok(),
+                   Some(ServerCommand::Goto { x: 100, y: -200 }));
+        assert_eq!(serde_json::from_str::<ServerCommand>(r#"{"command": "save_waypoint", "name": "dock"}"#).ok(),
+                   Some(ServerCommand::SaveWaypoint { name: String::from("dock"), tags: Vec::new(), heading: false }));
+        assert_eq!(serde_json::from_str::<ServerCommand>(r#"{"command": "stop"}"#).ok(), Some(ServerCommand::Stop));
+        assert!(serde_json::from_str::<ServerCommand>(r#"{"command": "explode"}"#).is_err());
+    }
+
+    #[test]
+    fn publishes_telemetry_as_json() {
+        let pose = RobotPose { x: 10, y: -20, angle: Angle::from_degrees(90.0) };
+        let event = ServerEvent::from_message(&Message::Position(pose)).unwrap();
+
+        assert_eq!(serde_json::to_string(&event).unwrap(), r#"{"type":"pose","x":10,"y":-20,"angle":90.0}"#);
+        assert_eq!(ServerEvent::from_message(&Message::State(RobotState::Fwd)), Some(ServerEvent::State { state: String::from("Fwd") }));
+        assert_eq!(ServerEvent::from_message(&Message::Unknown { opcode: 131, payload: Vec::new() }), None);
+        assert_eq!(serde_json::to_string(&ServerEvent::Reply { ok: true, error: None }).unwrap(), r#"{"type":"reply","ok":true}"#);
+    }
+
+    #[test]
+    fn accepts_only_signed_commands() {
+        let simulator = Simulator::start("127.0.0.1:0", SimulatorSettings::default()).ok().unwrap();
+
+        let mut config = Config::new();
+        config.robot_address = simulator.local_addr().ip().to_string();
+        config.robot_port = simulator.local_addr().port().to_string();
+        config.private_key = String::from(KEY);
+
+        let robot = Robot::with_config(config, "").ok().unwrap();
+
+        let (tx, rx) = mpsc::channel();
+        let server = ws::WebSocket::new(move |out: ws::Sender| FleetServer { out, tx: tx.clone() })
+            .unwrap().bind("127.0.0.1:0").unwrap();
+
+        let url = format!("ws://{}", server.local_addr().unwrap());
+        thread::spawn(move || { let _ = server.run(); });
+        thread::spawn(move || run_bridge(robot, &url));
+
+        let mut replies = Vec::new();
+        let mut moving = false;
+
+        while let Ok(text) = rx.recv_timeout(Duration::new(5, 0)) {
+            if text.starts_with(r#"{"type":"reply""#) {
+                replies.push(text);
+            } else if text.starts_with(r#"{"type":"pose","x":0,"y":"#) && !text.contains(r#""y":0,"#) {
+                moving = true;
+            }
+
+            if replies.len() == 3 && moving {
+                break;
+            }
+        }
+
+        assert_eq!(replies, vec![
+            r#"{"type":"reply","ok":true}"#,
+            r#"{"type":"reply","ok":false,"error":"replayed command"}"#,
+            r#"{"type":"reply","ok":false,"error":"bad signature"}"#,
+        ]);
+        assert!(moving);
+    }
+
+    #[test]
+    fn replies_with_the_error() {
+        let simulator = Simulator::start("127.0.0.1:0", SimulatorSettings::default()).ok().unwrap();
+
+        let mut config = Config::new();
+        config.robot_address = simulator.local_addr().ip().to_string();
+        config.robot_port = simulator.local_addr().port().to_string();
+
+        let mut robot = Robot::with_config(config, "").ok().unwrap();
+
+        let command = ServerCommand::GotoWaypoint { name: String::from("moon") };
+        assert_eq!(execute(&mut robot, &command), Err(String::from("Unknown waypoint: moon")));
+        assert_eq!(execute(&mut robot, &ServerCommand::Stop), Ok(()));
+    }
+
+    fn robot_with_key(simulator: &Simulator) -> Robot {
+        let mut config = Config::new();
+        config.robot_address = simulator.local_addr().ip().to_string();
+        config.robot_port = simulator.local_addr().port().to_string();
+        config.private_key = String::from(KEY);
+        config.reconnect.initial_backoff_ms = 10;
+
+        Robot::with_config(config, "").ok().unwrap()
+    }
+
+    #[test]
+    fn retries_unreachable_servers() {
+        let simulator = Simulator::start("127.0.0.1:0", SimulatorSettings::default()).ok().unwrap();
+
+        let error = run_bridge(robot_with_key(&simulator), "http://127.0.0.1:1").err().unwrap();
+        assert!(error.to_string().ends_with("expected a ws:// or wss:// url, not http://"));
+        assert!(run_bridge(robot_with_key(&simulator), "fleet.example.com:8080").is_err());
+
+        // Nothing listens on port 1, which is no reason to give up
+        let robot = robot_with_key(&simulator);
+        let (tx, rx) = mpsc::channel();
+        thread::spawn(move || { let _ = tx.send(run_bridge(robot, "ws://127.0.0.1:1").is_ok()); });
+
+        assert!(rx.recv_timeout(Duration::from_millis(500)).is_err());
+    }
+
+    #[test]
+    fn refuses_to_run_without_a_key() {
+        let simulator = Simulator::start("127.0.0.1:0", SimulatorSettings::default()).ok().unwrap();
+
+        let mut config = Config::new();
+        config.robot_address = simulator.local_addr().ip().to_string();
+        config.robot_port = simulator.local_addr().port().to_string();
+
+        let robot = Robot::with_config(config, "").ok().unwrap();
+
+        assert!(run_bridge(robot, "ws://127.0.0.1:1").is_err());
+    }
+}
@@ -0,0 +1,254 @@
+
+/* Recording the frames exchanged with the robot, and serving them back over TCP */
+
+use super::*;
+use std::net::{TcpListener, Shutdown};
+use std::io::{self, BufRead, BufReader, Write};
+use std::sync::atomic::Ordering;
+use std::thread;
+use serde_json;
+
+/* Frames are written as lowercase hex in the capture file */
+pub mod hex {
+    use serde::{Deserialize, Deserializer, Serializer};
+    use serde::de::Error;
+
+    pub fn encode(bytes: &[u8]) -> String {
+        bytes.iter().map(|b| format!("{:02x}", b)).collect()
+    }
+
+    pub fn decode(hex: &str) -> Result<Vec<u8>, &'static str> {
+        if !hex.len().is_multiple_of(2) || !hex.is_ascii() {
+            return Err("odd number of hex digits");
+        }
+
+        (0..hex.len()).step_by(2)
+            .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).map_err(|_| "invalid hex digit"))
+            .collect()
+    }
+
+    pub fn serialize<S: Serializer>(bytes: &[u8], serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&encode(bytes))
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<u8>, D::Error> {
+        decode(&String::deserialize(deserializer)?).map_err(D::Error::custom)
+    }
+}
+
+impl CaptureWriter {
+    /* Creates (or truncates) the capture file. Timestamps count from now
+     */
+    pub fn create(capture_path: &str) -> Result<CaptureWriter, RobotError> {
+        match File::create(capture_path) {
+            Ok(file) => Ok(CaptureWriter { writer: LineWriter::new(file), started: Instant::now() }),
+            Err(e) => Err( RobotError::new(RobotErrorType::Write).with_path(capture_path).with_source(e) )
+        }
+    }
+
+    pub fn record(&mut self, direction: CaptureDirection, frame: &[u8]) -> Result<(), RobotError> {
+        let elapsed = self.started.elapsed();
+
+        let record = CaptureRecord {
+            time_ms: elapsed.as_secs() * 1000 + elapsed.subsec_nanos() as u64 / 1_000_000,
+            direction,
+            frame: frame.to_vec(),
+        };
+
+        let line = match serde_json::to_string(&record) {
+            Ok(s) => s,
+            Err(e) => { return Err( RobotError::new(RobotErrorType::Write).with_source(e) ) }
+        };
+
+        match writeln!(self.writer, "{}", line) {
+            Ok(_) => Ok(()),
+            Err(e) => Err( RobotError::new(RobotErrorType::Write).with_source(e) )
+        }
+    }
+}
+
+impl CaptureRecord {
+    /* Reads every record of a capture file, in the order they were written
+     */
+    pub fn load(capture_path: &str) -> Result<Vec<CaptureRecord>, ConfigError> {
+        let capture_file = match File::open(capture_path) {
+            Ok(s) => s,
+            Err(e) => { return Err( ConfigError::from(e).with_path(capture_path) ) }
+        };
+
+        let mut records = Vec::new();
+
+        for (number, line) in BufReader::new(capture_file).lines().enumerate() {
+            let line = match line {
+                Ok(s) => s,
+                Err(e) => { return Err( ConfigError::new(ConfigErrorType::Read).with_path(capture_path).with_source(e) ) }
+            };
+
+            if line.trim().is_empty() {
+                continue;
+            }
+
+            match serde_json::from_str(&line) {
+                Ok(record) => records.push(record),
+                Err(e) => {
+                    return Err( ConfigError::new(ConfigErrorType::Deserialization)
+                        .with_path(capture_path)
+                        .with_source(format!("line {}: {}", number + 1, e)) )
+                }
+            }
+        }
+
+        Ok(records)
+    }
+}
+
+impl Replayer {
+    /* Listens on `addr`, and plays the received frames of `records` to every client
+     * connecting, keeping their original timing divided by `speed`. A speed of 0 sends
+     * them all at once. The client is disconnected at the end of the capture.
+     */
+    pub fn start(addr: &str, records: Vec<CaptureRecord>, speed: f64) -> Result<Replayer, RobotError> {
+        let listener = match TcpListener::bind(addr) {
+            Ok(s) => s,
+            Err(e) => { return Err( RobotError::new(RobotErrorType::Connection).with_address(addr).with_source(e) ) }
+        };
+
+        let addr = match listener.local_addr() {
+            Ok(s) => s,
+            Err(e) => { return Err( RobotError::new(RobotErrorType::Connection).with_address(addr).with_source(e) ) }
+        };
+
+        let running = Arc::new(AtomicBool::new(true));
+        let records: Arc<Vec<CaptureRecord>> = Arc::new(records.into_iter()
+            .filter(|r| r.direction == CaptureDirection::Received)
+            .collect());
+
+        let thread = {
+            let running = Arc::clone(&running);
+            thread::spawn(move || accept_clients(listener, records, speed, running))
+        };
+
+        Ok(Replayer {
+            addr,
+            running,
+            thread: Some(thread),
+        })
+    }
+
+    pub fn local_addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    /* Blocks until the replayer is stopped
+     */
+    pub fn wait(mut self) {
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+
+    /* Stops accepting clients, and cuts short the replays in progress
+     */
+    pub fn stop(&mut self) {
+        self.running.store(false, Ordering::SeqCst);
+
+        // Wake up the accepting thread, so it notices
+        let _ = TcpStream::connect(self.addr);
+
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+impl Drop for Replayer {
+    fn drop(&mut self) {
+        self.stop();
+    }
+}
+
+fn accept_clients(listener: TcpListener, records: Arc<Vec<CaptureRecord>>, speed: f64, running: Arc<AtomicBool>) {
+    for stream in listener.incoming() {
+        if !running.load(Ordering::SeqCst) {
+            break;
+        }
+
+        let stream = match stream {
+            Ok(s) => s,
+            Err(_) => continue,
+        };
+
+        // Whatever the client sends is ignored
+        if let Ok(mut s) = stream.try_clone() {
+            thread::spawn(move || io::copy(&mut s, &mut io::sink()));
+        }
+
+        let records = Arc::clone(&records);
+        let running = Arc::clone(&running);
+
+        thread::spawn(move || replay(stream, &records, speed, &running));
+    }
+}
+
+fn replay(mut stream: TcpStream, records: &[CaptureRecord], speed: f64, running: &AtomicBool) {
+    let mut last_ms = records.first().map_or(0, |r| r.time_ms);
+
+    for record in records {
+        if speed > 0.0 && record.time_ms > last_ms {
+            thread::sleep(Duration::from_millis(((record.time_ms - last_ms) as f64 / speed) as u64));
+        }
+
+        last_ms = record.time_ms;
+
+        if !running.load(Ordering::SeqCst) || stream.write_all(&record.frame).is_err() {
+            break;
+        }
+    }
+
+    let _ = stream.shutdown(Shutdown::Both);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::fs;
+
+    #[test]
+    fn records_round_trip_as_hex() {
+        let record = CaptureRecord { time_ms: 1500, direction: CaptureDirection::Sent, frame: vec![57, 0, 1, 0] };
+        let line = serde_json::to_string(&record).unwrap();
+
+        assert_eq!(line, r#"{"time_ms":1500,"direction":"sent","frame":"39000100"}"#);
+        assert_eq!(serde_json::from_str::<CaptureRecord>(&line).unwrap(), record);
+        assert!(serde_json::from_str::<CaptureRecord>(r#"{"time_ms":0,"direction":"sent","frame":"390"}"#).is_err());
+    }
+
+    #[test]
+    fn replays_received_frames() {
+        let capture_path = env::temp_dir().join(format!("pulurobot-capture-{}", std::process::id()));
+        let capture_path = capture_path.to_str().unwrap();
+
+        let state = Message::State(RobotState::Fwd).encode();
+        let battery = Message::Battery(BatteryStatus { charging: true, finished: false, voltage: 15000, percentage: 80 }).encode();
+
+        {
+            let mut capture = CaptureWriter::create(capture_path).ok().unwrap();
+            capture.record(CaptureDirection::Received, &state).ok().unwrap();
+            capture.record(CaptureDirection::Sent, &Command::Stop.encode()).ok().unwrap();
+            capture.record(CaptureDirection::Received, &battery).ok().unwrap();
+        }
+
+        let records = CaptureRecord::load(capture_path).ok().unwrap();
+        fs::remove_file(capture_path).unwrap();
+
+        assert_eq!(records.len(), 3);
+
+        let replayer = Replayer::start("127.0.0.1:0", records, 0.0).ok().unwrap();
+        let mut reader = FrameReader::new(TcpStream::connect(replayer.local_addr()).unwrap());
+
+        assert_eq!(reader.read_message().ok(), Some(Message::State(RobotState::Fwd)));
+        assert_eq!(reader.read_frame().ok().map(|f| f.encode()), Some(battery));
+        assert!(reader.read_frame().is_err());
+    }
+}
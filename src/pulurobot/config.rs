@@ -1,21 +1,28 @@
-use super::{Config, ConfigHandler, ConfigError, ConfigErrorType, ConfigErrorHandler};
+use super::{Config, ConfigHandler, ConfigError, ConfigErrorType, ConfigErrorHandler, ReconnectConfig, Waypoint};
 
 use std::path::Path;
+use std::collections::BTreeMap;
 use std::fs::{File, OpenOptions};
 use std::io::Read;
 use serde_json;
 
 impl ConfigHandler for Config {
     fn new() -> Self {
-        return Config {
+        Config {
             name: String::new(),
             manufacturer: String::new(),
+            server_address: String::new(),
+            server_port: String::new(),
+            server_tls: false,
+            private_key: String::new(),
             robot_address: String::new(),
             robot_port: String::new(),
-            point_a_x: 0,
-            point_a_y: 0,
-            point_b_x: 0,
-            point_b_y: 0,
+            waypoints: BTreeMap::new(),
+            reconnect: ReconnectConfig::default(),
+            point_a_x: None,
+            point_a_y: None,
+            point_b_x: None,
+            point_b_y: None,
         }
     }
 
@@ -38,33 +45,32 @@ impl ConfigHandler for Config {
 
     /* Reads a config file (in json format), and returns a Config object
      */
-    fn from_file(self, config_path:&str) -> Result<Self, ConfigError> {
-
-        let config:Config;
+    fn from_file(config_path:&str) -> Result<Self, ConfigError> {
 
         if Path::new(config_path).exists() {
             let mut config_file = match File::open(config_path) {
                 Ok(s) => s,
-                Err(_) => { return Err( ConfigError::new(ConfigErrorType::FileNotFound) ) }
+                Err(e) => { return Err( ConfigError::from(e).with_path(config_path) ) }
             };
 
             let mut config_data = String::new();
 
             match config_file.read_to_string(&mut config_data) {
                 Ok(_) => {},
-                Err(_) => { return Err( ConfigError::new(ConfigErrorType::Read) ) }
+                Err(e) => { return Err( ConfigError::new(ConfigErrorType::Read).with_path(config_path).with_source(e) ) }
             };
 
-            config = match serde_json::from_str(&config_data) {
-                Ok(s) => s,
-                Err(_) => { return Err( ConfigError::new(ConfigErrorType::Deserialization) ) }
-            };
+            match serde_json::from_str::<Config>(&config_data) {
+                Ok(mut s) => {
+                    s.migrate_points();
+                    Ok(s)
+                },
+                Err(e) => Err( ConfigError::from(e).with_path(config_path) )
+            }
 
         } else {
-            return Err( ConfigError::new(ConfigErrorType::FileNotFound) );
+            Err( ConfigError::new(ConfigErrorType::FileNotFound).with_path(config_path) )
         }
-
-        return Ok(config);
     }
 
     /* Writes the Config object to the file specified by config_path
@@ -73,54 +79,124 @@ impl ConfigHandler for Config {
 
         let config_file = match OpenOptions::new().write(true).truncate(true).open(config_path) {
             Ok(s) => s,
-            Err(_) => { return Err( ConfigError::new(ConfigErrorType::Open) ) }
+            Err(e) => { return Err( ConfigError::new(ConfigErrorType::Open).with_path(config_path).with_source(e) ) }
         };
 
         match serde_json::ser::to_writer_pretty(&config_file, &self) {
             Ok(_) => println!("Written config file"),
-            Err(_) => { return Err( ConfigError::new(ConfigErrorType::Serialization) ) }
+            Err(e) => { return Err( ConfigError::new(ConfigErrorType::Serialization).with_path(config_path).with_source(e) ) }
         };
 
-        config_file.sync_all().unwrap();
+        if let Err(e) = config_file.sync_all() {
+            return Err( ConfigError::new(ConfigErrorType::Serialization).with_path(config_path).with_source(e) );
+        }
 
-        return Ok(());
+        Ok(())
     }
 
-    /* Set coordinates for point "a" or "b"
+    /* Adds a waypoint, or replaces the one with the same name
      */
-    fn set_point(&mut self, name: &str, x: i32, y: i32) -> Result<(), ConfigError> {
-
-        if name == "a" {
-            self.point_a_x = x;
-            self.point_a_y = y;
-        } else if name == "b" {
-            self.point_b_x = x;
-            self.point_b_y = y;
+    fn set_waypoint(&mut self, name: &str, waypoint: Waypoint) -> Result<(), ConfigError> {
+        self.waypoints.insert(name.to_owned(), waypoint);
+        Ok(())
+    }
+
+    fn get_waypoint(&self, name: &str) -> Result<Waypoint, ConfigError> {
+        match self.waypoints.get(name) {
+            Some(waypoint) => Ok(waypoint.clone()),
+            None => Err( ConfigError::new(ConfigErrorType::WaypointNotFound).with_waypoint(name) )
         }
+    }
 
-        return Ok(());
+    /* Renames a waypoint, refusing to overwrite another one
+     */
+    fn rename_waypoint(&mut self, name: &str, new_name: &str) -> Result<(), ConfigError> {
+        if self.waypoints.contains_key(new_name) {
+            return Err( ConfigError::new(ConfigErrorType::WaypointExists).with_waypoint(new_name) );
+        }
+
+        let waypoint = self.delete_waypoint(name)?;
+        self.waypoints.insert(new_name.to_owned(), waypoint);
+
+        Ok(())
     }
 
-    /* Get coordinates for point "a" or "b"
+    /* Removes a waypoint, returning it
      */
-    fn get_point(&mut self, name: &str) -> Result<(i32,i32), ConfigError> {
+    fn delete_waypoint(&mut self, name: &str) -> Result<Waypoint, ConfigError> {
+        match self.waypoints.remove(name) {
+            Some(waypoint) => Ok(waypoint),
+            None => Err( ConfigError::new(ConfigErrorType::WaypointNotFound).with_waypoint(name) )
+        }
+    }
+}
 
-        let x:i32;
-        let y:i32;
+impl Config {
+    /* Moves points A and B of an older config file into the waypoints
+     */
+    fn migrate_points(&mut self) {
+        if let (Some(x), Some(y)) = (self.point_a_x.take(), self.point_a_y.take()) {
+            self.waypoints.entry(String::from("a")).or_insert(Waypoint { x, y, heading: None, tags: Vec::new() });
+        }
 
-        if name == "a" {
-            x = self.point_a_x;
-            y = self.point_a_y;
-        } else if name == "b" {
-            x = self.point_b_x;
-            y = self.point_b_y;
-        } else {
-            return Err( ConfigError::new(ConfigErrorType::Read) );
+        if let (Some(x), Some(y)) = (self.point_b_x.take(), self.point_b_y.take()) {
+            self.waypoints.entry(String::from("b")).or_insert(Waypoint { x, y, heading: None, tags: Vec::new() });
         }
+    }
+}
 
-        return Ok((x,y))
+impl Default for ReconnectConfig {
+    fn default() -> Self {
+        ReconnectConfig {
+            initial_backoff_ms: 500,
+            max_backoff_ms: 30_000,
+            queue_commands: false,
+            max_command_age_ms: 10_000,
+            read_timeout_ms: 5_000,
+        }
     }
 }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn waypoint(x: i32, y: i32) -> Waypoint {
+        Waypoint { x, y, heading: None, tags: Vec::new() }
+    }
+
+    #[test]
+    fn migrates_points_a_and_b() {
+        let mut config: Config = serde_json::from_str(r#"{
+            "name": "R2D2",
+            "manufacturer": "Pulurobotics Oy Ltd",
+            "robot_address": "192.168.43.23",
+            "robot_port": "22222",
+            "point_a_x": -256,
+            "point_a_y": -3083,
+            "point_b_x": -1261,
+            "point_b_y": -1630
+        }"#).unwrap();
+
+        config.migrate_points();
+
+        assert_eq!(config.get_waypoint("a").ok(), Some(waypoint(-256, -3083)));
+        assert_eq!(config.get_waypoint("b").ok(), Some(waypoint(-1261, -1630)));
+        assert!(!serde_json::to_string(&config).unwrap().contains("point_a_x"));
+    }
 
+    #[test]
+    fn renames_and_deletes_waypoints() {
+        let mut config = Config::new();
+        config.set_waypoint("kitchen", waypoint(1, 2)).ok();
+        config.set_waypoint("lobby", waypoint(3, 4)).ok();
 
+        assert!(config.rename_waypoint("kitchen", "lobby").is_err());
+        assert!(config.rename_waypoint("kitchen", "coffee").is_ok());
+        assert_eq!(config.get_waypoint("coffee").ok(), Some(waypoint(1, 2)));
+        assert!(config.get_waypoint("kitchen").is_err());
+
+        assert_eq!(config.delete_waypoint("lobby").ok(), Some(waypoint(3, 4)));
+        assert!(config.delete_waypoint("lobby").is_err());
+    }
+}
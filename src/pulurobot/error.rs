@@ -1,11 +1,23 @@
 /* Some implementation of low-level error handling */
 
-use super::{RobotErrorHandler, RobotError, RobotErrorType, ConfigErrorHandler, ConfigError, ConfigErrorType};
+use super::{RobotErrorHandler, RobotError, RobotErrorType, ConfigErrorHandler, ConfigError, ConfigErrorType,
+            ProtocolError, ProtocolErrorKind, PROTOCOL_ERROR_BYTES};
+use super::protocol::payload_len;
+use super::capture::hex;
+use std::error::Error;
+use std::fmt;
+use std::io;
+use serde_json;
 
 impl RobotErrorHandler for RobotError {
     fn new(error_type: RobotErrorType) -> Self {
         RobotError {
             err_type: error_type,
+            address: None,
+            opcode: None,
+            path: None,
+            protocol: None,
+            source: None,
         }
     }
 }
@@ -14,6 +26,288 @@ impl ConfigErrorHandler for ConfigError {
     fn new(error_type: ConfigErrorType) -> Self {
         ConfigError {
             err_type: error_type,
+            path: None,
+            waypoint: None,
+            source: None,
         }
     }
 }
+
+impl RobotError {
+    /* Exit code for command-line tools giving up because of this error, see the README
+     */
+    pub fn exit_code(&self) -> i32 {
+        match self.err_type {
+            RobotErrorType::Connection => 10,
+            RobotErrorType::Write => 11,
+            RobotErrorType::Read => 12,
+            RobotErrorType::Protocol => 13,
+            RobotErrorType::Timeout => 14,
+            RobotErrorType::Authentication => 15,
+            RobotErrorType::NotYetImplemented => 16,
+            RobotErrorType::NotArrived => 1,
+            RobotErrorType::Config => 3,
+        }
+    }
+
+    pub fn with_address<A: fmt::Display>(mut self, address: A) -> Self {
+        self.address = Some(address.to_string());
+        self
+    }
+
+    pub fn with_opcode(mut self, opcode: u8) -> Self {
+        self.opcode = Some(opcode);
+        self
+    }
+
+    pub fn with_path(mut self, path: &str) -> Self {
+        self.path = Some(path.to_owned());
+        self
+    }
+
+    pub fn with_source<E: Into<Box<dyn Error + Send + Sync>>>(mut self, source: E) -> Self {
+        self.source = Some(source.into());
+        self
+    }
+}
+
+impl ConfigError {
+    pub fn with_path(mut self, path: &str) -> Self {
+        self.path = Some(path.to_owned());
+        self
+    }
+
+    pub fn with_waypoint(mut self, name: &str) -> Self {
+        self.waypoint = Some(name.to_owned());
+        self
+    }
+
+    pub fn with_source<E: Into<Box<dyn Error + Send + Sync>>>(mut self, source: E) -> Self {
+        self.source = Some(source.into());
+        self
+    }
+}
+
+/* E.g. "Unable to write to 10.0.0.5:22222 (opcode 56): Broken pipe (os error 32)"
+ */
+impl fmt::Display for RobotError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let (what, preposition) = match self.err_type {
+            RobotErrorType::Connection => ("Unable to connect", "to"),
+            RobotErrorType::Write => ("Unable to write", "to"),
+            RobotErrorType::Read => ("Unable to read", "from"),
+            RobotErrorType::Protocol => ("Unexpected data", "from"),
+            RobotErrorType::Timeout => ("Timed out", "waiting for"),
+            RobotErrorType::Authentication => ("Unable to authenticate", "with"),
+            RobotErrorType::NotYetImplemented => ("Not yet implemented", "for"),
+            // Says where in the source, e.g. "blocked on the way to waypoint dock"
+            RobotErrorType::NotArrived => ("The robot did not arrive", ""),
+            // The config error says it all
+            RobotErrorType::Config => match self.source {
+                Some(ref source) => return write!(f, "{}", source),
+                None => ("Invalid configuration", "for"),
+            },
+        };
+
+        write!(f, "{}", what)?;
+
+        if let Some(ref path) = self.path {
+            write!(f, " {} {}", preposition, path)?;
+        } else if let Some(ref address) = self.address {
+            write!(f, " {} {}", preposition, address)?;
+        } else if !matches!(self.err_type, RobotErrorType::NotYetImplemented | RobotErrorType::Config | RobotErrorType::NotArrived) {
+            write!(f, " {} the robot", preposition)?;
+        }
+
+        if let Some(opcode) = self.opcode {
+            write!(f, " (opcode {})", opcode)?;
+        }
+
+        if let Some(ref protocol) = self.protocol {
+            write!(f, ": {}", protocol)
+        } else if let Some(ref source) = self.source {
+            write!(f, ": {}", source)
+        } else {
+            Ok(())
+        }
+    }
+}
+
+impl Error for RobotError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self.protocol {
+            Some(ref protocol) => Some(protocol.as_ref()),
+            None => self.source.as_ref().map(|e| e.as_ref() as &(dyn Error + 'static)),
+        }
+    }
+}
+
+/* Io errors from the connection, sorted by what they mean for the robot
+ */
+impl From<io::Error> for RobotError {
+    fn from(error: io::Error) -> Self {
+        let error_type = match error.kind() {
+            io::ErrorKind::TimedOut | io::ErrorKind::WouldBlock => RobotErrorType::Timeout,
+            io::ErrorKind::ConnectionRefused | io::ErrorKind::ConnectionReset | io::ErrorKind::ConnectionAborted
+                | io::ErrorKind::NotConnected | io::ErrorKind::AddrNotAvailable | io::ErrorKind::AddrInUse => RobotErrorType::Connection,
+            io::ErrorKind::BrokenPipe | io::ErrorKind::WriteZero => RobotErrorType::Write,
+            _ => RobotErrorType::Read,
+        };
+
+        RobotError::new(error_type).with_source(error)
+    }
+}
+
+/* E.g. "Unknown waypoint: kitchen", or "Invalid contents in config/config: expected
+ * `,` at line 3 column 5"
+ */
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let path = self.path.as_ref().map_or("file", |p| p.as_str());
+        let waypoint = self.waypoint.as_ref().map_or("", |w| w.as_str());
+
+        match self.err_type {
+            ConfigErrorType::Read => write!(f, "Unable to read {}", path)?,
+            ConfigErrorType::Open => write!(f, "Unable to open {} for writing", path)?,
+            ConfigErrorType::FileNotFound => write!(f, "No such file: {}", path)?,
+            ConfigErrorType::Deserialization => write!(f, "Invalid contents in {}", path)?,
+            ConfigErrorType::Serialization => write!(f, "Unable to write {}", path)?,
+            ConfigErrorType::WaypointNotFound => write!(f, "Unknown waypoint: {}", waypoint)?,
+            ConfigErrorType::WaypointExists => write!(f, "Waypoint already exists: {}", waypoint)?,
+        }
+
+        // The io error of a missing file would only say it again
+        match self.source {
+            Some(ref source) if self.err_type != ConfigErrorType::FileNotFound => write!(f, ": {}", source),
+            _ => Ok(()),
+        }
+    }
+}
+
+impl Error for ConfigError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        self.source.as_ref().map(|e| e.as_ref() as &(dyn Error + 'static))
+    }
+}
+
+impl From<io::Error> for ConfigError {
+    fn from(error: io::Error) -> Self {
+        let error_type = match error.kind() {
+            io::ErrorKind::NotFound => ConfigErrorType::FileNotFound,
+            _ => ConfigErrorType::Read,
+        };
+
+        ConfigError::new(error_type).with_source(error)
+    }
+}
+
+impl From<serde_json::Error> for ConfigError {
+    fn from(error: serde_json::Error) -> Self {
+        let error_type = if error.is_io() { ConfigErrorType::Read } else { ConfigErrorType::Deserialization };
+
+        ConfigError::new(error_type).with_source(error)
+    }
+}
+
+impl From<ConfigError> for RobotError {
+    fn from(error: ConfigError) -> Self {
+        RobotError::new(RobotErrorType::Config).with_source(error)
+    }
+}
+
+impl From<ProtocolError> for RobotError {
+    fn from(error: ProtocolError) -> Self {
+        RobotError {
+            opcode: Some(error.opcode),
+            protocol: Some(Box::new(error)),
+            ..RobotError::new(RobotErrorType::Protocol)
+        }
+    }
+}
+
+impl ProtocolError {
+    /* An error about the frame made of `opcode` and `payload`
+     */
+    pub fn new(kind: ProtocolErrorKind, opcode: u8, payload: &[u8]) -> ProtocolError {
+        let mut bytes = vec![opcode, (payload.len() >> 8) as u8, payload.len() as u8];
+        bytes.extend(payload.iter().take(PROTOCOL_ERROR_BYTES - bytes.len()));
+
+        ProtocolError { kind, opcode, length: payload.len(), bytes }
+    }
+}
+
+impl fmt::Display for ProtocolError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.kind {
+            ProtocolErrorKind::Truncated => {
+                write!(f, "Frame with opcode {} ends before its {} byte payload", self.opcode, self.length)?
+            },
+            ProtocolErrorKind::BadLength => match payload_len(self.opcode) {
+                Some((min, max)) if min == max => {
+                    write!(f, "Frame with opcode {} has a {} byte payload, expected {}", self.opcode, self.length, min)?
+                },
+                Some((min, max)) => {
+                    write!(f, "Frame with opcode {} has a {} byte payload, expected {} to {}", self.opcode, self.length, min, max)?
+                },
+                None => write!(f, "Frame with opcode {} has a {} byte payload", self.opcode, self.length)?,
+            },
+            ProtocolErrorKind::BadValue => write!(f, "Frame with opcode {} holds an invalid value", self.opcode)?,
+            ProtocolErrorKind::UnknownOpcode => write!(f, "Unknown opcode {}", self.opcode)?,
+            ProtocolErrorKind::Garbage => write!(f, "Skipped {} bytes not making up a frame", self.length)?,
+        }
+
+        write!(f, ": {}", hex::encode(&self.bytes))
+    }
+}
+
+impl Error for ProtocolError {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pulurobot::GotoOutcome;
+
+    #[test]
+    fn describes_robot_errors_with_context() {
+        let refused = io::Error::new(io::ErrorKind::ConnectionRefused, "Connection refused");
+        let error = RobotError::from(refused).with_address("10.0.0.5:22222");
+
+        assert_eq!(error.err_type, RobotErrorType::Connection);
+        assert_eq!(error.to_string(), "Unable to connect to 10.0.0.5:22222: Connection refused");
+        assert!(error.source().is_some());
+
+        let error = RobotError::new(RobotErrorType::Timeout).with_opcode(130);
+        assert_eq!(error.to_string(), "Timed out waiting for the robot (opcode 130)");
+        assert_eq!(error.exit_code(), 14);
+
+        let error = RobotError::new(RobotErrorType::NotArrived)
+            .with_source(format!("{} on the way to waypoint dock", GotoOutcome::Blocked));
+        assert_eq!(error.to_string(), "The robot did not arrive: blocked on the way to waypoint dock");
+        assert_eq!(error.exit_code(), 1);
+
+        let error = RobotError::new(RobotErrorType::Write).with_path("/tmp/capture.jsonl");
+        assert_eq!(error.to_string(), "Unable to write to /tmp/capture.jsonl");
+
+        let error = RobotError::from(ProtocolError::new(ProtocolErrorKind::BadLength, 139, &[1, 2]));
+        assert_eq!(error.to_string(), "Unexpected data from the robot (opcode 139): \
+                                       Frame with opcode 139 has a 2 byte payload, expected 1: 8b00020102");
+    }
+
+    #[test]
+    fn describes_config_errors() {
+        let error = ConfigError::new(ConfigErrorType::WaypointNotFound).with_waypoint("kitchen");
+        assert_eq!(error.to_string(), "Unknown waypoint: kitchen");
+
+        // Waypoint errors pass through robot errors unchanged
+        let error = RobotError::from(error);
+        assert_eq!((error.err_type, error.exit_code()), (RobotErrorType::Config, 3));
+        assert_eq!(error.to_string(), "Unknown waypoint: kitchen");
+
+        let error = ConfigError::from(serde_json::from_str::<u8>("{").err().unwrap()).with_path("config/config");
+        assert_eq!(error.err_type, ConfigErrorType::Deserialization);
+        assert!(error.to_string().starts_with("Invalid contents in config/config: "));
+
+        let missing = ConfigError::from(io::Error::new(io::ErrorKind::NotFound, "gone")).with_path("m.json");
+        assert_eq!(missing.to_string(), "No such file: m.json");
+    }
+}
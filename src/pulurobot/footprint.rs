@@ -0,0 +1,78 @@
+
+/* Where the robot's body is around its reported position, as sent in the 140 message */
+
+use super::*;
+
+impl RobotInfo {
+    /* Distance in mm from the reported position to the farthest corner of the body, i.e.
+     * the room the robot needs to turn on the spot
+     */
+    pub fn turning_radius(&self) -> f64 {
+        let x = (self.xoffs as f64).abs() + self.xs as f64 / 2.0;
+        let y = (self.yoffs as f64).abs() + self.ys as f64 / 2.0;
+
+        x.hypot(y)
+    }
+
+    /* Whether the point `ahead` mm in front of and `left` mm to the left of the reported
+     * position is under the body
+     */
+    pub fn covers(&self, ahead: f64, left: f64) -> bool {
+        self.sweeps(ahead, left, 0.0, 0.0)
+    }
+
+    /* Whether the body, grown by `margin` mm on every side, passes over the point `ahead`
+     * mm in front of and `left` mm to the left of the reported position while driving
+     * `distance` mm straight ahead, or back when negative
+     */
+    pub fn sweeps(&self, ahead: f64, left: f64, distance: f64, margin: f64) -> bool {
+        let back = self.xoffs as f64 - self.xs as f64 / 2.0 - margin + distance.min(0.0);
+        let front = self.xoffs as f64 + self.xs as f64 / 2.0 + margin + distance.max(0.0);
+
+        ahead >= back && ahead <= front && (left - self.yoffs as f64).abs() <= self.ys as f64 / 2.0 + margin
+    }
+
+    /* How far the robot, grown by `margin` mm, can drive toward `distance` before the
+     * point `ahead` mm in front of and `left` mm to the left of it gets under the body.
+     * None if it never does
+     */
+    pub fn distance_to(&self, ahead: f64, left: f64, distance: f64, margin: f64) -> Option<f64> {
+        if !self.sweeps(ahead, left, distance, margin) {
+            return None;
+        }
+
+        let gap = if distance < 0.0 {
+            (self.xoffs as f64 - self.xs as f64 / 2.0 - margin) - ahead
+        } else {
+            ahead - (self.xoffs as f64 + self.xs as f64 / 2.0 + margin)
+        };
+
+        Some(gap.max(0.0))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const INFO: RobotInfo = RobotInfo { xs: 480, ys: 400, xoffs: -120, yoffs: 0 };
+
+    #[test]
+    fn places_the_body_behind_the_wheels() {
+        assert!(INFO.covers(120.0, 200.0));
+        assert!(INFO.covers(-360.0, -200.0));
+        assert!(!INFO.covers(121.0, 0.0));
+        assert!(!INFO.covers(0.0, 201.0));
+        assert_eq!(INFO.turning_radius().round(), 412.0);
+
+        assert!(INFO.sweeps(600.0, 0.0, 500.0, 0.0));
+        assert!(!INFO.sweeps(600.0, 0.0, -500.0, 0.0));
+        assert!(INFO.sweeps(-800.0, 0.0, -500.0, 0.0));
+        assert!(INFO.sweeps(0.0, 220.0, 0.0, 20.0));
+
+        assert_eq!(INFO.distance_to(620.0, 50.0, 1000.0, 0.0), Some(500.0));
+        assert_eq!(INFO.distance_to(-560.0, 50.0, -1000.0, 0.0), Some(200.0));
+        assert_eq!(INFO.distance_to(620.0, 250.0, 1000.0, 0.0), None);
+        assert_eq!(INFO.distance_to(0.0, 0.0, 1000.0, 0.0), Some(0.0));
+    }
+}
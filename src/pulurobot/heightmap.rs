@@ -0,0 +1,427 @@
+
+/* Decoding and rendering of the height maps made by the robot's 3D TOF sensors */
+
+use super::*;
+use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+
+/* Bytes before the cells of a height map payload */
+pub(super) const HEADER_LEN: usize = 15;
+
+/* Largest chunk of data in a stored (uncompressed) deflate block */
+const MAX_STORED_BLOCK: usize = 65535;
+
+impl From<u8> for HeightMapCell {
+    fn from(t: u8) -> HeightMapCell {
+        match t {
+            0 => HeightMapCell::Unseen,
+            1 => HeightMapCell::Floor,
+            2 => HeightMapCell::Threshold,
+            3 => HeightMapCell::SmallDrop,
+            4 => HeightMapCell::SmallItem,
+            5 => HeightMapCell::BigDrop,
+            6 => HeightMapCell::LowCeiling,
+            7 => HeightMapCell::BigItem,
+            8 => HeightMapCell::Wall,
+            n => HeightMapCell::Unknown(n),
+        }
+    }
+}
+
+impl From<HeightMapCell> for u8 {
+    fn from(cell: HeightMapCell) -> u8 {
+        match cell {
+            HeightMapCell::Unseen => 0,
+            HeightMapCell::Floor => 1,
+            HeightMapCell::Threshold => 2,
+            HeightMapCell::SmallDrop => 3,
+            HeightMapCell::SmallItem => 4,
+            HeightMapCell::BigDrop => 5,
+            HeightMapCell::LowCeiling => 6,
+            HeightMapCell::BigItem => 7,
+            HeightMapCell::Wall => 8,
+            HeightMapCell::Unknown(n) => n,
+        }
+    }
+}
+
+impl HeightMapCell {
+    /* Whether the robot can't drive through the cell. Thresholds and small drops are
+     * fine, unseen cells are not known to be blocked
+     */
+    pub fn is_obstacle(&self) -> bool {
+        matches!(*self, HeightMapCell::SmallItem | HeightMapCell::BigDrop | HeightMapCell::LowCeiling
+                        | HeightMapCell::BigItem | HeightMapCell::Wall)
+    }
+
+    /* How the cell is drawn in the ascii preview
+     */
+    pub fn symbol(&self) -> char {
+        match *self {
+            HeightMapCell::Unseen => ' ',
+            HeightMapCell::Floor => '.',
+            HeightMapCell::Threshold => '-',
+            HeightMapCell::SmallDrop => 'v',
+            HeightMapCell::SmallItem => 'o',
+            HeightMapCell::BigDrop => 'V',
+            HeightMapCell::LowCeiling => '^',
+            HeightMapCell::BigItem => 'O',
+            HeightMapCell::Wall => '#',
+            HeightMapCell::Unknown(_) => '?',
+        }
+    }
+
+    /* Grey level in images, darker being more of an obstacle
+     */
+    pub fn grey(&self) -> u8 {
+        match *self {
+            HeightMapCell::Unseen => 128,
+            HeightMapCell::Floor => 255,
+            HeightMapCell::Threshold => 220,
+            HeightMapCell::SmallDrop => 190,
+            HeightMapCell::SmallItem => 100,
+            HeightMapCell::BigDrop => 60,
+            HeightMapCell::LowCeiling => 40,
+            HeightMapCell::BigItem => 20,
+            HeightMapCell::Wall => 0,
+            HeightMapCell::Unknown(_) => 128,
+        }
+    }
+
+    /* Colour in png images: floor in white, things sticking up in red and drops in blue
+     */
+    pub fn colour(&self) -> [u8; 3] {
+        match *self {
+            HeightMapCell::Unseen => [128, 128, 128],
+            HeightMapCell::Floor => [255, 255, 255],
+            HeightMapCell::Threshold => [255, 230, 150],
+            HeightMapCell::SmallDrop => [150, 190, 255],
+            HeightMapCell::SmallItem => [255, 140, 100],
+            HeightMapCell::BigDrop => [30, 60, 200],
+            HeightMapCell::LowCeiling => [200, 100, 220],
+            HeightMapCell::BigItem => [220, 30, 30],
+            HeightMapCell::Wall => [0, 0, 0],
+            HeightMapCell::Unknown(_) => [255, 0, 255],
+        }
+    }
+}
+
+impl HeightMap {
+    /* Decodes the payload of a 138 message
+     */
+    pub fn from_payload(mut payload: &[u8]) -> Result<HeightMap, ProtocolError> {
+        let whole = payload;
+
+        if payload.len() < HEADER_LEN {
+            return Err( ProtocolError::new(ProtocolErrorKind::BadLength, OPCODE_TOF_HMAP, whole) );
+        }
+
+        let xsamps = payload.read_u16::<BigEndian>().unwrap();
+        let ysamps = payload.read_u16::<BigEndian>().unwrap();
+        let angle = Angle::from_raw(payload.read_i16::<BigEndian>().unwrap());
+        let xorig = payload.read_i32::<BigEndian>().unwrap();
+        let yorig = payload.read_i32::<BigEndian>().unwrap();
+        let unit_size = payload.read_u8().unwrap();
+
+        let len = xsamps as usize * ysamps as usize;
+
+        // Cells must fit the payload exactly
+        if payload.len() != len {
+            return Err( ProtocolError::new(ProtocolErrorKind::BadValue, OPCODE_TOF_HMAP, whole) );
+        }
+
+        Ok(HeightMap {
+            xsamps,
+            ysamps,
+            angle,
+            xorig,
+            yorig,
+            unit_size,
+            cells: payload[..len].iter().map(|&cell| HeightMapCell::from(cell)).collect(),
+        })
+    }
+
+    /* Appends the payload of a 138 message. Inverse of from_payload
+     */
+    pub fn write_payload(&self, payload: &mut Vec<u8>) {
+        payload.write_u16::<BigEndian>(self.xsamps).unwrap();
+        payload.write_u16::<BigEndian>(self.ysamps).unwrap();
+        payload.write_i16::<BigEndian>(self.angle.raw()).unwrap();
+        payload.write_i32::<BigEndian>(self.xorig).unwrap();
+        payload.write_i32::<BigEndian>(self.yorig).unwrap();
+        payload.push(self.unit_size);
+        payload.extend(self.cells.iter().map(|&cell| u8::from(cell)));
+    }
+
+    /* The cell in column `x` and row `y`, if within the map
+     */
+    pub fn get(&self, x: usize, y: usize) -> Option<HeightMapCell> {
+        if x < self.xsamps as usize && y < self.ysamps as usize {
+            self.cells.get(y * self.xsamps as usize + x).cloned()
+        } else {
+            None
+        }
+    }
+
+    /* Column and row of the cell the robot is in
+     */
+    pub fn robot_cell(&self) -> (usize, usize) {
+        (self.xsamps as usize / 2, self.ysamps as usize / 2)
+    }
+
+    /* Rows from the top, i.e. the left of the robot, calling `cell` for each cell
+     */
+    fn render<T, F: Fn(HeightMapCell) -> T>(&self, cell: F) -> Vec<Vec<T>> {
+        (0..self.ysamps as usize).rev()
+            .map(|y| (0..self.xsamps as usize).map(|x| cell(self.get(x, y).unwrap_or(HeightMapCell::Unseen))).collect())
+            .collect()
+    }
+
+    /* How far the robot can drive `distance` mm straight ahead, or back when negative,
+     * before reaching an obstacle. None if the way is clear, as far as the map shows.
+     * Cells count as obstacles if any part of them is in the way
+     */
+    pub fn obstacle_in_path(&self, info: &RobotInfo, distance: f64) -> Option<f64> {
+        let (robot_x, robot_y) = self.robot_cell();
+        let unit = self.unit_size as f64;
+        let mut nearest: Option<f64> = None;
+
+        for y in 0..self.ysamps as usize {
+            for x in 0..self.xsamps as usize {
+                if !self.get(x, y).is_some_and(|cell| cell.is_obstacle()) {
+                    continue;
+                }
+
+                let ahead = (x as f64 - robot_x as f64) * unit;
+                let left = (y as f64 - robot_y as f64) * unit;
+
+                if let Some(gap) = info.distance_to(ahead, left, distance, unit / 2.0) {
+                    nearest = Some(nearest.map_or(gap, |n| n.min(gap)));
+                }
+            }
+        }
+
+        nearest
+    }
+
+    /* One line of text per row, with ahead of the robot to the right and the robot
+     * itself drawn as R. Given the robot's footprint, the floor under the rest of its
+     * body is drawn as r. See HeightMapCell::symbol
+     */
+    pub fn to_ascii(&self, footprint: Option<&RobotInfo>) -> String {
+        let mut rows = self.render(|cell| cell.symbol());
+        let (x, y) = self.robot_cell();
+        let unit = self.unit_size as f64;
+
+        if let Some(info) = footprint {
+            for (top, row) in rows.iter_mut().enumerate() {
+                let left = (self.ysamps as f64 - 1.0 - top as f64 - y as f64) * unit;
+
+                for (column, symbol) in row.iter_mut().enumerate() {
+                    let ahead = (column as f64 - x as f64) * unit;
+
+                    if info.covers(ahead, left) && (*symbol == ' ' || *symbol == '.') {
+                        *symbol = 'r';
+                    }
+                }
+            }
+        }
+
+        let top = rows.len().saturating_sub(y + 1);
+
+        if let Some(robot) = rows.get_mut(top).and_then(|row| row.get_mut(x)) {
+            *robot = 'R';
+        }
+
+        rows.into_iter().map(|row| row.into_iter().collect::<String>() + "\n").collect()
+    }
+
+    /* A binary greyscale PGM image, laid out like to_ascii, `scale` pixels to a cell
+     */
+    pub fn to_pgm(&self, scale: usize) -> Vec<u8> {
+        let rows = scale_rows(self.render(|cell| vec![cell.grey()]), scale);
+
+        let mut image = format!("P5\n{} {}\n255\n", self.xsamps as usize * scale, rows.len()).into_bytes();
+        image.extend(rows.into_iter().flat_map(|row| row.into_iter()));
+
+        image
+    }
+
+    /* An RGB PNG image, laid out like to_ascii, `scale` pixels to a cell. See
+     * HeightMapCell::colour
+     */
+    pub fn to_png(&self, scale: usize) -> Vec<u8> {
+        let rows = scale_rows(self.render(|cell| cell.colour().to_vec()), scale);
+
+        let mut header = Vec::new();
+        header.write_u32::<BigEndian>((self.xsamps as usize * scale) as u32).unwrap();
+        header.write_u32::<BigEndian>(rows.len() as u32).unwrap();
+        // 8 bits per channel, RGB, default compression, filtering and no interlacing
+        header.extend(&[8, 2, 0, 0, 0]);
+
+        // Every row starts with its filter type, none
+        let mut data = Vec::new();
+
+        for row in rows {
+            data.push(0);
+            data.extend(row);
+        }
+
+        let mut image = vec![0x89, b'P', b'N', b'G', b'\r', b'\n', 0x1a, b'\n'];
+        png_chunk(&mut image, b"IHDR", &header);
+        png_chunk(&mut image, b"IDAT", &zlib_stored(&data));
+        png_chunk(&mut image, b"IEND", &[]);
+
+        image
+    }
+}
+
+/* Repeats every pixel, and every row, `scale` times
+ */
+fn scale_rows(rows: Vec<Vec<Vec<u8>>>, scale: usize) -> Vec<Vec<u8>> {
+    let mut scaled = Vec::new();
+
+    for row in rows {
+        let line: Vec<u8> = row.iter()
+            .flat_map(|pixel| (0..scale).flat_map(move |_| pixel.iter().cloned()))
+            .collect();
+
+        for _ in 0..scale {
+            scaled.push(line.clone());
+        }
+    }
+
+    scaled
+}
+
+fn crc32(bytes: &[u8]) -> u32 {
+    let mut crc = 0xffff_ffffu32;
+
+    for &byte in bytes {
+        crc ^= byte as u32;
+
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 { (crc >> 1) ^ 0xedb8_8320 } else { crc >> 1 };
+        }
+    }
+
+    !crc
+}
+
+fn adler32(bytes: &[u8]) -> u32 {
+    let (mut a, mut b) = (1u32, 0u32);
+
+    for &byte in bytes {
+        a = (a + byte as u32) % 65521;
+        b = (b + a) % 65521;
+    }
+
+    (b << 16) | a
+}
+
+/* Wraps `data` in a zlib stream without compressing it, which every PNG reader
+ * understands and keeps us from needing a deflate implementation
+ */
+fn zlib_stored(data: &[u8]) -> Vec<u8> {
+    let mut stream = vec![0x78, 0x01];
+    let mut blocks = data.chunks(MAX_STORED_BLOCK).peekable();
+
+    if blocks.peek().is_none() {
+        stream.extend(&[1, 0, 0, 0xff, 0xff]);
+    }
+
+    while let Some(block) = blocks.next() {
+        let last = blocks.peek().is_none();
+
+        stream.push(if last { 1 } else { 0 });
+        stream.write_u16::<byteorder::LittleEndian>(block.len() as u16).unwrap();
+        stream.write_u16::<byteorder::LittleEndian>(!(block.len() as u16)).unwrap();
+        stream.extend(block);
+    }
+
+    stream.write_u32::<BigEndian>(adler32(data)).unwrap();
+
+    stream
+}
+
+fn png_chunk(image: &mut Vec<u8>, kind: &[u8; 4], data: &[u8]) {
+    image.write_u32::<BigEndian>(data.len() as u32).unwrap();
+
+    let start = image.len();
+    image.extend(kind);
+    image.extend(data);
+
+    let crc = crc32(&image[start..]);
+    image.write_u32::<BigEndian>(crc).unwrap();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn map() -> HeightMap {
+        HeightMap {
+            xsamps: 3,
+            ysamps: 2,
+            angle: Angle::from_degrees(90.0),
+            xorig: 1000,
+            yorig: -2000,
+            unit_size: 40,
+            cells: vec![1, 1, 8, 0, 5, 42].into_iter().map(HeightMapCell::from).collect(),
+        }
+    }
+
+    #[test]
+    fn decodes_payload() {
+        let payload = [0, 3, 0, 2, 0x40, 0, 0, 0, 0x03, 0xe8, 0xff, 0xff, 0xf8, 0x30, 40, 1, 1, 8, 0, 5, 42];
+        let map = HeightMap::from_payload(&payload).ok().unwrap();
+
+        assert_eq!(map, self::map());
+        assert_eq!(map.get(2, 0), Some(HeightMapCell::Wall));
+        assert_eq!(map.get(2, 1), Some(HeightMapCell::Unknown(42)));
+        assert_eq!(map.get(3, 0), None);
+
+        let mut written = Vec::new();
+        map.write_payload(&mut written);
+        assert_eq!(written, payload.to_vec());
+
+        assert!(HeightMap::from_payload(&payload[..20]).is_err());
+    }
+
+    #[test]
+    fn renders_the_map() {
+        assert_eq!(map().to_ascii(None), " R?\n..#\n");
+        assert_eq!(map().to_ascii(Some(&RobotInfo { xs: 80, ys: 40, xoffs: -40, yoffs: 0 })), "rR?\n..#\n");
+        assert_eq!(map().to_pgm(1), b"P5\n3 2\n255\n\x80\x3c\x80\xff\xff\x00".to_vec());
+        assert_eq!(map().to_pgm(2).len(), b"P5\n6 4\n255\n".len() + 24);
+    }
+
+    #[test]
+    fn finds_obstacles_in_the_way() {
+        let info = RobotInfo { xs: 60, ys: 60, xoffs: 0, yoffs: 0 };
+        let mut cells = vec![HeightMapCell::Floor; 15];
+        cells[4] = HeightMapCell::Wall;
+        let map = HeightMap { xsamps: 5, ysamps: 3, cells, ..map() };
+
+        // The wall is 80mm ahead and 40mm right, its near edge 30mm from the front
+        assert_eq!(map.obstacle_in_path(&info, 500.0), Some(30.0));
+        assert_eq!(map.obstacle_in_path(&info, -500.0), None);
+        assert_eq!(map.obstacle_in_path(&RobotInfo { ys: 10, ..info }, 500.0), None);
+    }
+
+    #[test]
+    fn writes_valid_png() {
+        assert_eq!(crc32(b"IEND"), 0xae42_6082);
+        assert_eq!(adler32(b"Wikipedia"), 0x11e6_0398);
+
+        let png = map().to_png(1);
+
+        assert_eq!(&png[..8], b"\x89PNG\r\n\x1a\n");
+        assert_eq!(&png[12..16], b"IHDR");
+        assert_eq!(&png[png.len() - 12..], b"\x00\x00\x00\x00IEND\xae\x42\x60\x82");
+
+        // A row is the filter byte and 3 pixels, stored in one block
+        let idat = &png[33..];
+        assert_eq!(&idat[4..8], b"IDAT");
+        assert_eq!(&idat[8..15], &[0x78, 0x01, 1, 20, 0, !20, 0xff]);
+    }
+}
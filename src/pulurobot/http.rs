@@ -0,0 +1,371 @@
+
+/* A small HTTP/JSON API for controlling the robot, e.g. from a web dashboard or curl */
+
+use super::*;
+use std::net::TcpListener;
+use std::io::{BufRead, BufReader, Write};
+use std::thread;
+use serde::Serialize;
+use serde_json;
+
+/* Largest request body accepted, in bytes */
+const MAX_BODY_LEN: usize = 64 * 1024;
+
+/* Longest request or header line accepted, in bytes */
+const MAX_LINE_LEN: usize = 8 * 1024;
+
+/* Most headers accepted in a request */
+const MAX_HEADERS: usize = 100;
+
+/* How long a client may take to send its request */
+const REQUEST_TIMEOUT_SECS: u64 = 10;
+
+struct HttpRequest {
+    method: String,
+    path: String,
+    body: Vec<u8>,
+}
+
+struct HttpResponse {
+    status: u16,
+    body: String,
+}
+
+#[derive(Serialize)]
+struct PoseBody {
+    x: i32,
+    y: i32,
+    /* Degrees */
+    angle: f64,
+}
+
+#[derive(Serialize)]
+struct StateBody {
+    state: String,
+}
+
+#[derive(Serialize)]
+struct OkBody {
+    ok: bool,
+}
+
+#[derive(Serialize)]
+struct ErrorBody<'a> {
+    error: &'a str,
+}
+
+/* Body of POST /goto: either coordinates or the name of a waypoint */
+#[derive(Deserialize)]
+struct GotoBody {
+    x: Option<i32>,
+    y: Option<i32>,
+    waypoint: Option<String>,
+}
+
+impl HttpResponse {
+    fn json<T: Serialize>(status: u16, body: &T) -> HttpResponse {
+        match serde_json::to_string(body) {
+            Ok(body) => HttpResponse { status, body },
+            Err(_) => HttpResponse::error(500, "unable to encode response"),
+        }
+    }
+
+    fn error(status: u16, error: &str) -> HttpResponse {
+        HttpResponse {
+            status,
+            body: serde_json::to_string(&ErrorBody { error }).unwrap_or_default(),
+        }
+    }
+
+    /* The answer to a robot call, mapping its errors onto status codes. The body holds
+     * the whole error, e.g. the robot's address and the io error underneath
+     */
+    fn from_result<T: Serialize>(result: Result<T, RobotError>) -> HttpResponse {
+        let e = match result {
+            Ok(body) => { return HttpResponse::json(200, &body) },
+            Err(e) => e,
+        };
+
+        let status = match e.err_type {
+            RobotErrorType::Timeout => 504,
+            RobotErrorType::Config => match e.source.as_ref().and_then(|s| s.downcast_ref::<ConfigError>()) {
+                Some(c) if c.err_type == ConfigErrorType::WaypointNotFound => 404,
+                _ => 400,
+            },
+            _ => 503,
+        };
+
+        HttpResponse::error(status, &e.to_string())
+    }
+
+    fn reason(&self) -> &'static str {
+        match self.status {
+            200 => "OK",
+            400 => "Bad Request",
+            404 => "Not Found",
+            405 => "Method Not Allowed",
+            413 => "Payload Too Large",
+            431 => "Request Header Fields Too Large",
+            503 => "Service Unavailable",
+            504 => "Gateway Timeout",
+            _ => "Internal Server Error",
+        }
+    }
+
+    fn write_to<W: Write>(&self, writer: &mut W) -> std::io::Result<()> {
+        write!(writer, "HTTP/1.1 {} {}\r\n", self.status, self.reason())?;
+        write!(writer, "Content-Type: application/json\r\n")?;
+        write!(writer, "Content-Length: {}\r\n", self.body.len())?;
+        write!(writer, "Connection: close\r\n\r\n")?;
+        writer.write_all(self.body.as_bytes())
+    }
+}
+
+/* Reads a line of at most MAX_LINE_LEN bytes into `line`, so that a client can't make
+ * us buffer an endless one. `what` names the line for the error response.
+ */
+fn read_line<R: BufRead>(reader: &mut R, line: &mut String, what: &str) -> Result<(), HttpResponse> {
+    line.clear();
+
+    match reader.by_ref().take(MAX_LINE_LEN as u64).read_line(line) {
+        Ok(_) if line.len() >= MAX_LINE_LEN && !line.ends_with('\n') => {
+            Err(HttpResponse::error(431, &format!("{} too long", what)))
+        },
+        Ok(_) => Ok(()),
+        Err(_) => Err(HttpResponse::error(400, &format!("unable to read {}", what))),
+    }
+}
+
+/* Reads a request line, the headers and a body of Content-Length bytes. Returns a
+ * response instead if the request is unusable.
+ */
+fn read_request<R: BufRead>(reader: &mut R) -> Result<HttpRequest, HttpResponse> {
+    let mut line = String::new();
+
+    read_line(reader, &mut line, "request line")?;
+
+    let (method, path) = {
+        let mut parts = line.split_whitespace();
+
+        match (parts.next(), parts.next()) {
+            (Some(method), Some(path)) => (method.to_owned(), path.to_owned()),
+            _ => { return Err(HttpResponse::error(400, "invalid request line")) }
+        }
+    };
+
+    let mut content_length = 0;
+    let mut headers = 0;
+
+    loop {
+        read_line(reader, &mut line, "header")?;
+
+        let header = line.trim_end();
+
+        if header.is_empty() {
+            break;
+        }
+
+        headers += 1;
+
+        if headers > MAX_HEADERS {
+            return Err(HttpResponse::error(431, "too many headers"));
+        }
+
+        if let Some(colon) = header.find(':') {
+            if header[..colon].eq_ignore_ascii_case("content-length") {
+                content_length = match header[colon + 1..].trim().parse() {
+                    Ok(n) => n,
+                    Err(_) => { return Err(HttpResponse::error(400, "invalid Content-Length")) }
+                };
+            }
+        }
+    }
+
+    if content_length > MAX_BODY_LEN {
+        return Err(HttpResponse::error(413, "request body too large"));
+    }
+
+    let mut body = vec![0; content_length];
+
+    if reader.read_exact(&mut body).is_err() {
+        return Err(HttpResponse::error(400, "unable to read body"));
+    }
+
+    // Query strings are not used by any endpoint
+    let path = match path.find('?') {
+        Some(i) => path[..i].to_owned(),
+        None => path,
+    };
+
+    Ok(HttpRequest { method, path, body })
+}
+
+fn goto(robot: &mut Robot, body: &[u8]) -> HttpResponse {
+    let body: GotoBody = match serde_json::from_slice(body) {
+        Ok(b) => b,
+        Err(_) => { return HttpResponse::error(400, "expected {\"x\": X, \"y\": Y} or {\"waypoint\": NAME}") }
+    };
+
+    let (x, y) = match (body.x, body.y, body.waypoint) {
+        (Some(x), Some(y), None) => (x, y),
+        (None, None, Some(name)) => match robot.config.get_waypoint(&name) {
+            Ok(w) => (w.x, w.y),
+            Err(e) => { return HttpResponse::from_result::<OkBody>(Err(RobotError::from(e))) }
+        },
+        _ => { return HttpResponse::error(400, "expected {\"x\": X, \"y\": Y} or {\"waypoint\": NAME}") }
+    };
+
+    HttpResponse::from_result(robot.goto(x, y).map(|_| OkBody { ok: true }))
+}
+
+/* Calls through to the robot for a request
+ */
+fn route(robot: &mut Robot, request: &HttpRequest) -> HttpResponse {
+    let method = request.method.as_str();
+
+    match (method, request.path.as_str()) {
+        ("GET", "/pose") => HttpResponse::from_result(robot.get_pose().map(|pose| PoseBody {
+            x: pose.x,
+            y: pose.y,
+            angle: pose.angle.degrees(),
+        })),
+        ("GET", "/battery") => HttpResponse::from_result(robot.get_battery()),
+        ("GET", "/state") => HttpResponse::from_result(robot.get_state().map(|state| StateBody { state: format!("{:?}", state) })),
+        ("GET", "/waypoints") => HttpResponse::json(200, &robot.config.waypoints),
+        ("POST", "/goto") => goto(robot, &request.body),
+        ("POST", "/stop") => HttpResponse::from_result(robot.stop().map(|_| OkBody { ok: true })),
+        ("POST", "/free") => HttpResponse::from_result(robot.free().map(|_| OkBody { ok: true })),
+        ("POST", "/localize") => HttpResponse::from_result(robot.localize().map(|_| OkBody { ok: true })),
+        (_, "/pose") | (_, "/battery") | (_, "/state") | (_, "/waypoints")
+            | (_, "/goto") | (_, "/stop") | (_, "/free") | (_, "/localize") => HttpResponse::error(405, "method not allowed"),
+        _ => HttpResponse::error(404, "not found"),
+    }
+}
+
+fn handle_client(stream: TcpStream, robot: &Mutex<Robot>) {
+    let _ = stream.set_read_timeout(Some(Duration::new(REQUEST_TIMEOUT_SECS, 0)));
+
+    let mut writer = match stream.try_clone() {
+        Ok(s) => s,
+        Err(_) => return,
+    };
+
+    let response = match read_request(&mut BufReader::new(stream)) {
+        Ok(request) => route(&mut robot.lock().unwrap(), &request),
+        Err(response) => response,
+    };
+
+    let _ = response.write_to(&mut writer);
+}
+
+/* Serves the HTTP API on `addr`, e.g. "127.0.0.1:8080", blocking for as long as the
+ * listener works. See the README for the endpoints.
+ */
+pub fn serve_http(robot: Robot, addr: &str) -> Result<(), RobotError> {
+    let listener = match TcpListener::bind(addr) {
+        Ok(s) => s,
+        Err(e) => { return Err( RobotError::new(RobotErrorType::Connection).with_address(addr).with_source(e) ) }
+    };
+
+    serve_http_on(robot, listener);
+
+    Ok(())
+}
+
+/* Serves the HTTP API on an already bound listener, e.g. one on port 0
+ */
+pub fn serve_http_on(robot: Robot, listener: TcpListener) {
+    let robot = Arc::new(Mutex::new(robot));
+
+    for stream in listener.incoming() {
+        let stream = match stream {
+            Ok(s) => s,
+            Err(_) => continue,
+        };
+
+        let robot = Arc::clone(&robot);
+        thread::spawn(move || handle_client(stream, &robot));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{Cursor, Read};
+
+    fn request(addr: &SocketAddr, request: &str) -> String {
+        let mut stream = TcpStream::connect(addr).unwrap();
+        stream.write_all(request.as_bytes()).unwrap();
+
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+
+        response
+    }
+
+    #[test]
+    fn reads_requests() {
+        let mut input = Cursor::new(&b"POST /goto?now HTTP/1.1\r\nHost: robot\r\ncontent-length: 7\r\n\r\n{\"x\":1}"[..]);
+        let request = read_request(&mut input).ok().unwrap();
+
+        assert_eq!(request.method, "POST");
+        assert_eq!(request.path, "/goto");
+        assert_eq!(request.body, b"{\"x\":1}");
+
+        let mut input = Cursor::new(&b"GARBAGE\r\n\r\n"[..]);
+        assert_eq!(read_request(&mut input).err().map(|r| r.status), Some(400));
+
+        let long = format!("GET /pose HTTP/1.1\r\nCookie: {}\r\n\r\n", "a".repeat(MAX_LINE_LEN));
+        assert_eq!(read_request(&mut Cursor::new(long.as_bytes())).err().map(|r| r.status), Some(431));
+
+        let many = format!("GET /pose HTTP/1.1\r\n{}\r\n", "X-A: b\r\n".repeat(MAX_HEADERS + 1));
+        assert_eq!(read_request(&mut Cursor::new(many.as_bytes())).err().map(|r| r.status), Some(431));
+    }
+
+    #[test]
+    fn answers_errors_with_their_text() {
+        let timeout = RobotError::new(RobotErrorType::Timeout).with_address("10.0.0.5:22222").with_opcode(139);
+        let response = HttpResponse::from_result::<OkBody>(Err(timeout));
+        assert_eq!(response.status, 504);
+        assert_eq!(response.body, r#"{"error":"Timed out waiting for 10.0.0.5:22222 (opcode 139)"}"#);
+
+        let unwritable = RobotError::from(ConfigError::new(ConfigErrorType::Open).with_path("config/config"));
+        assert_eq!(HttpResponse::from_result::<OkBody>(Err(unwritable)).status, 400);
+
+        let refused = RobotError::new(RobotErrorType::Connection);
+        assert_eq!(HttpResponse::from_result::<OkBody>(Err(refused)).status, 503);
+    }
+
+    #[test]
+    fn serves_the_robot() {
+        let simulator = Simulator::start("127.0.0.1:0", SimulatorSettings::default()).ok().unwrap();
+
+        let mut config = Config::new();
+        config.robot_address = simulator.local_addr().ip().to_string();
+        config.robot_port = simulator.local_addr().port().to_string();
+        config.set_waypoint("dock", Waypoint { x: 0, y: 0, heading: None, tags: Vec::new() }).ok();
+
+        let robot = Robot::with_config(config, "").ok().unwrap();
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        thread::spawn(move || serve_http_on(robot, listener));
+
+        let pose = request(&addr, "GET /pose HTTP/1.1\r\n\r\n");
+        assert!(pose.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(pose.ends_with(r#"{"x":0,"y":0,"angle":0.0}"#));
+
+        let state = request(&addr, "GET /state HTTP/1.1\r\n\r\n");
+        assert!(state.ends_with(r#"{"state":"Idle"}"#));
+
+        let goto = request(&addr, "POST /goto HTTP/1.1\r\nContent-Length: 20\r\n\r\n{\"waypoint\":\"dock\"}\n");
+        assert!(goto.ends_with(r#"{"ok":true}"#));
+
+        let unknown = request(&addr, "POST /goto HTTP/1.1\r\nContent-Length: 20\r\n\r\n{\"waypoint\":\"moon\"}\n");
+        assert!(unknown.starts_with("HTTP/1.1 404 Not Found\r\n"));
+        assert!(unknown.ends_with(r#"{"error":"Unknown waypoint: moon"}"#));
+
+        assert!(request(&addr, "GET /stop HTTP/1.1\r\n\r\n").starts_with("HTTP/1.1 405 "));
+        assert!(request(&addr, "GET /nowhere HTTP/1.1\r\n\r\n").starts_with("HTTP/1.1 404 "));
+    }
+}
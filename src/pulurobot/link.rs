@@ -0,0 +1,471 @@
+
+/* Supervision of the TCP connection to the robot: reading, reconnecting and queueing */
+
+use super::*;
+use std::net::{TcpStream, Shutdown, SocketAddr};
+use std::io::Write;
+use std::thread;
+use std::mem;
+
+/* How long to wait for the robot to accept a connection */
+const CONNECT_TIMEOUT_SECS: u64 = 5;
+
+fn robot_addr(config: &Config) -> Result<SocketAddr, RobotError> {
+    let addr = config.robot_address.to_owned() + ":" + &config.robot_port;
+
+    match addr.parse() {
+        Ok(s) => Ok(s),
+        Err(e) => Err( RobotError::new(RobotErrorType::Connection).with_address(addr).with_source(e) )
+    }
+}
+
+/* Connects to the robot, and returns the stream to read from and a clone to write to.
+ * Reads give up after the configured read timeout, so a silently dropped connection
+ * doesn't block the supervisor forever
+ */
+fn connect_stream(addr: &SocketAddr, reconnect: &ReconnectConfig) -> Result<(TcpStream, TcpStream), RobotError> {
+    let stream = match TcpStream::connect_timeout(addr, Duration::new(CONNECT_TIMEOUT_SECS, 0)) {
+        Ok(s) => s,
+        Err(e) => { return Err( RobotError::new(RobotErrorType::Connection).with_address(addr).with_source(e) ) }
+    };
+
+    let read_timeout = match reconnect.read_timeout_ms {
+        0 => None,
+        ms => Some(Duration::from_millis(ms)),
+    };
+
+    if let Err(e) = stream.set_read_timeout(read_timeout) {
+        return Err( RobotError::new(RobotErrorType::Connection).with_address(addr).with_source(e) );
+    }
+
+    match stream.try_clone() {
+        Ok(s) => Ok((stream, s)),
+        Err(e) => Err( RobotError::new(RobotErrorType::Connection).with_address(addr).with_source(e) )
+    }
+}
+
+/* Doubles the reconnect delay, up to the configured maximum
+ */
+pub fn next_backoff(delay: Duration, max: Duration) -> Duration {
+    if delay * 2 > max { max } else { delay * 2 }
+}
+
+/* Calls the low-battery callbacks whose threshold the battery has dropped below
+ */
+fn check_battery_alarms(alarms: &mut [BatteryAlarm], battery: &BatteryStatus) {
+    for alarm in alarms.iter_mut() {
+        if battery.percentage < alarm.threshold {
+            if !alarm.triggered {
+                alarm.triggered = true;
+                (alarm.callback)(battery);
+            }
+        } else {
+            alarm.triggered = false;
+        }
+    }
+}
+
+/* Forgets everything the robot reported over a connection which is gone, so that
+ * get_pose and friends wait for fresh values instead of acting on stale ones. The
+ * protocol counters are kept, as they cover the whole session
+ */
+fn forget_readings(telemetry: &mut Telemetry) {
+    let protocol = mem::take(&mut telemetry.protocol);
+    *telemetry = Telemetry { protocol, ..Telemetry::default() };
+}
+
+/* Connects to the robot, and hands the new connection over to a supervisor thread
+ * which keeps it alive until stop is called.
+ */
+pub fn start(robot: &Robot) -> Result<(), RobotError> {
+    let addr = robot_addr(&robot.config)?;
+    let (read_stream, write_stream) = connect_stream(&addr, &robot.config.reconnect)?;
+
+    let generation = {
+        let mut link = robot.link.lock().unwrap();
+        link.generation += 1;
+        link.stream = Some(write_stream);
+        link.flush_queue(Duration::from_millis(robot.config.reconnect.max_command_age_ms));
+        link.generation
+    };
+
+    forget_readings(&mut robot.telemetry.lock().unwrap());
+
+    let supervisor = Supervisor {
+        addr,
+        reconnect: robot.config.reconnect.clone(),
+        generation,
+        link: Arc::clone(&robot.link),
+        telemetry: Arc::clone(&robot.telemetry),
+        subscribers: Arc::clone(&robot.subscribers),
+        link_subscribers: Arc::clone(&robot.link_subscribers),
+        battery_alarms: Arc::clone(&robot.battery_alarms),
+    };
+
+    supervisor.notify(LinkState::Connected);
+    thread::spawn(move || supervisor.run(read_stream));
+
+    Ok(())
+}
+
+/* Closes the connection, and tells the supervisor thread to exit instead of reconnecting
+ */
+pub fn stop(link: &Mutex<Link>) {
+    let mut link = link.lock().unwrap();
+    link.generation += 1;
+
+    if let Some(stream) = link.stream.take() {
+        let _ = stream.shutdown(Shutdown::Both);
+    }
+}
+
+impl Link {
+    pub fn new() -> Self {
+        Link {
+            stream: None,
+            queue: VecDeque::new(),
+            generation: 0,
+            capture: None,
+            sent_at: None,
+        }
+    }
+
+    /* Adds a frame to the capture, if one is running. A capture which can't be
+     * written to any more is dropped, instead of failing the connection.
+     */
+    fn record(&mut self, direction: CaptureDirection, frame: &[u8]) {
+        let failed = match self.capture {
+            Some(ref mut capture) => capture.record(direction, frame).is_err(),
+            None => false,
+        };
+
+        if failed {
+            self.capture = None;
+        }
+    }
+
+    /* Writes a command to the robot. While disconnected the command is queued if the
+     * config allows it, otherwise a Connection error is returned.
+     */
+    pub fn send(&mut self, command: &Command, reconnect: &ReconnectConfig) -> Result<(), RobotError> {
+        let mut failure = None;
+
+        if let Some(mut stream) = self.stream.take() {
+            let buf = command.encode();
+
+            match stream.write_all(&buf) {
+                Ok(_) => {
+                    self.stream = Some(stream);
+                    self.sent_at = Some(Instant::now());
+                    self.record(CaptureDirection::Sent, &buf);
+                    return Ok(());
+                },
+                Err(e) => failure = Some(e),
+            }
+
+            // Make sure the supervisor notices the broken connection
+            let _ = stream.shutdown(Shutdown::Both);
+        }
+
+        if !reconnect.queue_commands {
+            let error = match failure {
+                Some(e) => RobotError::new(RobotErrorType::Write).with_source(e),
+                None => RobotError::new(RobotErrorType::Connection).with_source("not connected, and queue_commands is off"),
+            };
+
+            return Err( error.with_opcode(command.opcode()) );
+        }
+
+        self.queue.push_back((Instant::now(), command.clone()));
+
+        Ok(())
+    }
+
+    /* Sends the queued commands that are younger than max_age, and drops the rest
+     */
+    fn flush_queue(&mut self, max_age: Duration) {
+        while let Some((queued_at, command)) = self.queue.pop_front() {
+            if queued_at.elapsed() > max_age {
+                continue;
+            }
+
+            let buf = command.encode();
+
+            let sent = match self.stream {
+                Some(ref mut stream) => stream.write_all(&buf).is_ok(),
+                None => false,
+            };
+
+            if !sent {
+                self.queue.push_front((queued_at, command));
+                break;
+            }
+
+            self.sent_at = Some(Instant::now());
+            self.record(CaptureDirection::Sent, &buf);
+        }
+    }
+}
+
+/* Everything a supervisor thread needs to read from and restore the connection */
+struct Supervisor {
+    addr: SocketAddr,
+    reconnect: ReconnectConfig,
+    generation: u64,
+    link: Arc<Mutex<Link>>,
+    telemetry: Arc<Mutex<Telemetry>>,
+    subscribers: Arc<Mutex<Vec<Sender<Message>>>>,
+    link_subscribers: Arc<Mutex<Vec<Sender<LinkState>>>>,
+    battery_alarms: Arc<Mutex<Vec<BatteryAlarm>>>,
+}
+
+impl Supervisor {
+    fn is_current(&self) -> bool {
+        self.link.lock().unwrap().generation == self.generation
+    }
+
+    fn notify(&self, state: LinkState) {
+        // Drop subscribers which have hung up
+        self.link_subscribers.lock().unwrap().retain(|tx| tx.send(state.clone()).is_ok());
+    }
+
+    /* Reads from the connection until it is closed, then reconnects. Returns once the
+     * connection has been closed by stop.
+     */
+    fn run(self, mut stream: TcpStream) {
+        loop {
+            self.read_until_closed(&stream);
+
+            {
+                let mut link = self.link.lock().unwrap();
+
+                if link.generation != self.generation {
+                    return;
+                }
+
+                // The connection may still be open if it just went quiet, so close it
+                // before the robot sees a second one
+                if let Some(write_stream) = link.stream.take() {
+                    let _ = write_stream.shutdown(Shutdown::Both);
+                }
+            }
+
+            forget_readings(&mut self.telemetry.lock().unwrap());
+            self.notify(LinkState::Disconnected);
+
+            stream = match self.reconnect() {
+                Some(s) => s,
+                None => return,
+            };
+        }
+    }
+
+    /* Tries to reconnect with exponential backoff. Returns None if stopped meanwhile
+     */
+    fn reconnect(&self) -> Option<TcpStream> {
+        let max_backoff = Duration::from_millis(self.reconnect.max_backoff_ms);
+        let mut delay = Duration::from_millis(self.reconnect.initial_backoff_ms);
+        let mut attempt = 0;
+
+        loop {
+            attempt += 1;
+
+            self.notify(LinkState::Retrying { attempt, delay });
+            thread::sleep(delay);
+
+            if !self.is_current() {
+                return None;
+            }
+
+            if let Ok((read_stream, write_stream)) = connect_stream(&self.addr, &self.reconnect) {
+                {
+                    let mut link = self.link.lock().unwrap();
+
+                    if link.generation != self.generation {
+                        let _ = write_stream.shutdown(Shutdown::Both);
+                        return None;
+                    }
+
+                    link.stream = Some(write_stream);
+                    link.flush_queue(Duration::from_millis(self.reconnect.max_command_age_ms));
+                }
+
+                self.notify(LinkState::Connected);
+                return Some(read_stream);
+            }
+
+            delay = next_backoff(delay, max_backoff);
+        }
+    }
+
+    /* Decodes messages until the connection is closed, or nothing has arrived within the
+     * read timeout, updating the telemetry and passing every message on to the
+     * subscribers. Frames which can't be decoded are counted and skipped
+     */
+    fn read_until_closed(&self, stream: &TcpStream) {
+        let mut reader = FrameReader::new(stream);
+
+        loop {
+            let frame = match reader.read_frame() {
+                Ok(f) => f,
+                Err(RobotError { protocol: Some(error), .. }) => {
+                    // Keep the capture a faithful copy of the stream, garbage and all
+                    self.link.lock().unwrap().record(CaptureDirection::Received, reader.skipped());
+                    self.telemetry.lock().unwrap().protocol.add_error(*error);
+                    continue;
+                },
+                // Closed, broken or timed out: either way the link is dead
+                Err(_) => break,
+            };
+
+            self.link.lock().unwrap().record(CaptureDirection::Received, &frame.encode());
+
+            let message = match Message::from_payload(frame.opcode, &frame.payload) {
+                Ok(m) => m,
+                Err(error) => {
+                    self.telemetry.lock().unwrap().protocol.add_error(error);
+                    continue;
+                },
+            };
+
+            {
+                let mut telemetry = self.telemetry.lock().unwrap();
+                telemetry.protocol.frames += 1;
+
+                match message {
+                    Message::Position(pose) => telemetry.pose = Some(pose),
+                    Message::Battery(battery) => telemetry.battery = Some(battery),
+                    Message::State(state) => telemetry.state = Some(state),
+                    Message::TofHeightMap(ref map) => telemetry.height_map = Some(map.clone()),
+                    Message::RobotInfo(info) => telemetry.robot_info = Some(info),
+                    Message::Unknown { opcode, .. } => {
+                        *telemetry.protocol.unknown_opcodes.entry(opcode).or_insert(0) += 1;
+                    },
+                }
+            }
+
+            if let Message::Battery(ref battery) = message {
+                check_battery_alarms(&mut self.battery_alarms.lock().unwrap(), battery);
+            }
+
+            // Drop subscribers which have hung up
+            self.subscribers.lock().unwrap().retain(|tx| tx.send(message.clone()).is_ok());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::TcpListener;
+    use std::sync::mpsc;
+
+    #[test]
+    fn backoff_doubles_up_to_max() {
+        let max = Duration::from_millis(3000);
+        let mut delay = Duration::from_millis(500);
+        let mut delays = Vec::new();
+
+        for _ in 0..5 {
+            delay = next_backoff(delay, max);
+            delays.push(delay.as_secs() * 1000 + delay.subsec_nanos() as u64 / 1_000_000);
+        }
+
+        assert_eq!(delays, vec![1000, 2000, 3000, 3000, 3000]);
+    }
+
+    #[test]
+    fn queues_commands_while_disconnected() {
+        let mut link = Link::new();
+        let mut reconnect = ReconnectConfig::default();
+
+        assert!(link.send(&Command::Stop, &reconnect).is_err());
+        assert!(link.queue.is_empty());
+
+        reconnect.queue_commands = true;
+
+        assert!(link.send(&Command::Stop, &reconnect).is_ok());
+        assert_eq!(link.queue.len(), 1);
+    }
+
+    #[test]
+    fn battery_alarm_fires_once_per_discharge() {
+        let fired = Arc::new(Mutex::new(0));
+        let counter = Arc::clone(&fired);
+        let mut alarms = vec![BatteryAlarm {
+            threshold: 20,
+            callback: Box::new(move |_| *counter.lock().unwrap() += 1),
+            triggered: false,
+        }];
+
+        for percentage in &[25, 19, 18, 15, 30, 10] {
+            let battery = BatteryStatus { charging: false, finished: false, voltage: 14000, percentage: *percentage };
+            check_battery_alarms(&mut alarms, &battery);
+        }
+
+        assert_eq!(*fired.lock().unwrap(), 2);
+    }
+
+    #[test]
+    fn forgets_readings_when_disconnected() {
+        let mut simulator = Simulator::start("127.0.0.1:0", SimulatorSettings::default()).ok().unwrap();
+
+        let mut config = Config::new();
+        config.robot_address = simulator.local_addr().ip().to_string();
+        config.robot_port = simulator.local_addr().port().to_string();
+
+        let mut robot = Robot::with_config(config, "").ok().unwrap();
+        let states = robot.subscribe_link();
+
+        assert!(robot.get_pose().is_ok());
+        assert!(robot.get_battery().is_ok());
+
+        simulator.stop();
+
+        assert_eq!(states.recv_timeout(Duration::new(5, 0)).ok(), Some(LinkState::Disconnected));
+
+        let telemetry = robot.telemetry.lock().unwrap().clone();
+        assert_eq!(telemetry.pose, None);
+        assert_eq!(telemetry.battery, None);
+        assert!(telemetry.protocol.frames > 0);
+    }
+
+    #[test]
+    fn reconnects_when_the_robot_goes_quiet() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (tx, accepted) = mpsc::channel();
+
+        // Accepts connections, but never sends anything on them
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                if tx.send(stream).is_err() {
+                    break;
+                }
+            }
+        });
+
+        let mut config = Config::new();
+        config.robot_address = addr.ip().to_string();
+        config.robot_port = addr.port().to_string();
+        config.reconnect.read_timeout_ms = 200;
+        config.reconnect.initial_backoff_ms = 10;
+
+        let mut robot = Robot::with_config(config, "").ok().unwrap();
+        let states = robot.subscribe_link();
+        let _quiet = accepted.recv_timeout(Duration::new(5, 0)).unwrap();
+
+        assert_eq!(states.recv_timeout(Duration::new(5, 0)).ok(), Some(LinkState::Disconnected));
+        assert!(accepted.recv_timeout(Duration::new(5, 0)).is_ok());
+    }
+
+    #[test]
+    fn drops_expired_commands() {
+        let mut link = Link::new();
+        link.queue.push_back((Instant::now() - Duration::from_secs(60), Command::Free));
+
+        link.flush_queue(Duration::from_secs(10));
+
+        assert!(link.queue.is_empty());
+    }
+}
@@ -0,0 +1,351 @@
+
+/* Building a map of the site from the robot's height maps and positions */
+
+use super::*;
+use std::fs::{self, File};
+use std::io::BufWriter;
+use serde_json;
+
+/* Evidence added when a cell is seen blocked, or removed when seen free. Obstacles
+ * count for more, as the sensors miss them more often than they imagine them
+ */
+const OCCUPIED_STEP: i8 = 4;
+const FREE_STEP: i8 = 1;
+
+/* Evidence removed from a cell the robot drove over, which the sensors can't see. It
+ * is free for sure at the time, so it counts as free right away
+ */
+const VISITED_STEP: i8 = 2;
+
+/* Evidence never grows beyond this, so the map can change its mind when things move */
+const EVIDENCE_LIMIT: i8 = 40;
+
+/* Evidence needed before a cell counts as occupied, or free */
+const OCCUPIED_EVIDENCE: i8 = 3;
+const FREE_EVIDENCE: i8 = -2;
+
+/* Cells are saved as a list of [column, row, evidence] */
+pub mod cells {
+    use std::collections::BTreeMap;
+    use serde::{Deserialize, Deserializer, Serializer};
+    use serde::ser::SerializeSeq;
+
+    pub fn serialize<S: Serializer>(cells: &BTreeMap<(i32, i32), i8>, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut seq = serializer.serialize_seq(Some(cells.len()))?;
+
+        for (&(x, y), &evidence) in cells {
+            seq.serialize_element(&(x, y, evidence))?;
+        }
+
+        seq.end()
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<BTreeMap<(i32, i32), i8>, D::Error> {
+        let cells: Vec<(i32, i32, i8)> = Vec::deserialize(deserializer)?;
+
+        Ok(cells.into_iter().map(|(x, y, evidence)| ((x, y), evidence)).collect())
+    }
+}
+
+impl OccupancyGrid {
+    /* An empty map of `cell_size` mm squares
+     */
+    pub fn new(cell_size: i32) -> OccupancyGrid {
+        OccupancyGrid {
+            cell_size: cell_size.max(1),
+            cells: BTreeMap::new(),
+        }
+    }
+
+    /* Reads a map written by save
+     */
+    pub fn load(map_path: &str) -> Result<OccupancyGrid, ConfigError> {
+        let map_file = match File::open(map_path) {
+            Ok(s) => s,
+            Err(e) => { return Err( ConfigError::from(e).with_path(map_path) ) }
+        };
+
+        match serde_json::from_reader::<_, OccupancyGrid>(map_file) {
+            Ok(grid) if grid.cell_size > 0 => Ok(grid),
+            Ok(_) => Err( ConfigError::new(ConfigErrorType::Deserialization).with_path(map_path).with_source("cell_size must be positive") ),
+            Err(e) => Err( ConfigError::from(e).with_path(map_path) ),
+        }
+    }
+
+    /* Writes the map as json, replacing the file. It is written next to it first and
+     * then moved over it, so a crash halfway never leaves a broken map behind
+     */
+    pub fn save(&self, map_path: &str) -> Result<(), ConfigError> {
+        let temp_path = format!("{}.tmp", map_path);
+
+        let map_file = match File::create(&temp_path) {
+            Ok(s) => s,
+            Err(e) => { return Err( ConfigError::new(ConfigErrorType::Open).with_path(&temp_path).with_source(e) ) }
+        };
+
+        let mut writer = BufWriter::new(map_file);
+
+        if let Err(e) = serde_json::to_writer(&mut writer, self) {
+            return Err( ConfigError::new(ConfigErrorType::Serialization).with_path(&temp_path).with_source(e) );
+        }
+
+        let written = match writer.into_inner() {
+            Ok(file) => file.sync_all(),
+            Err(e) => Err(e.into_error()),
+        };
+
+        if let Err(e) = written.and_then(|_| fs::rename(&temp_path, map_path)) {
+            let _ = fs::remove_file(&temp_path);
+            return Err( ConfigError::new(ConfigErrorType::Serialization).with_path(map_path).with_source(e) );
+        }
+
+        Ok(())
+    }
+
+    /* Column and row of the cell holding the point (x, y) in mm
+     */
+    pub fn cell_at(&self, x: i32, y: i32) -> (i32, i32) {
+        (x.div_euclid(self.cell_size), y.div_euclid(self.cell_size))
+    }
+
+    /* The middle of a cell, in mm
+     */
+    pub fn cell_centre(&self, cell: (i32, i32)) -> RobotLocation {
+        RobotLocation {
+            x: cell.0 * self.cell_size + self.cell_size / 2,
+            y: cell.1 * self.cell_size + self.cell_size / 2,
+        }
+    }
+
+    /* Adds what a height map shows, placed at the position and heading it was made at.
+     * Unseen cells leave the map as it was
+     */
+    pub fn add_height_map(&mut self, map: &HeightMap) {
+        let (robot_x, robot_y) = map.robot_cell();
+        let unit = map.unit_size as f64;
+        let (sin, cos) = map.angle.radians().sin_cos();
+
+        for y in 0..map.ysamps as usize {
+            for x in 0..map.xsamps as usize {
+                let step = match map.get(x, y) {
+                    Some(cell) if cell.is_obstacle() => OCCUPIED_STEP,
+                    Some(HeightMapCell::Floor) | Some(HeightMapCell::Threshold)
+                        | Some(HeightMapCell::SmallDrop) => -FREE_STEP,
+                    _ => continue,
+                };
+
+                // From the robot's frame to the map's
+                let ahead = (x as f64 - robot_x as f64) * unit;
+                let left = (y as f64 - robot_y as f64) * unit;
+                let map_x = map.xorig as f64 + ahead * cos - left * sin;
+                let map_y = map.yorig as f64 + ahead * sin + left * cos;
+
+                let cell = self.cell_at(map_x.round() as i32, map_y.round() as i32);
+                self.add_evidence(cell, step);
+            }
+        }
+    }
+
+    /* Counts the cell holding the point (x, y) in mm as free, the robot having been there
+     */
+    pub fn add_visit(&mut self, x: i32, y: i32) {
+        let cell = self.cell_at(x, y);
+        self.add_evidence(cell, -VISITED_STEP);
+    }
+
+    fn add_evidence(&mut self, cell: (i32, i32), step: i8) {
+        let evidence = self.cells.entry(cell).or_insert(0);
+        *evidence = evidence.saturating_add(step).clamp(-EVIDENCE_LIMIT, EVIDENCE_LIMIT);
+    }
+
+    /* What is known about the cell holding the point (x, y) in mm
+     */
+    pub fn occupancy(&self, x: i32, y: i32) -> Occupancy {
+        match self.cells.get(&self.cell_at(x, y)) {
+            Some(&evidence) if evidence >= OCCUPIED_EVIDENCE => Occupancy::Occupied,
+            Some(&evidence) if evidence <= FREE_EVIDENCE => Occupancy::Free,
+            _ => Occupancy::Unknown,
+        }
+    }
+
+    /* Whether the point (x, y) in mm has been seen free. Unknown cells are not free
+     */
+    pub fn is_free(&self, x: i32, y: i32) -> bool {
+        self.occupancy(x, y) == Occupancy::Free
+    }
+
+    /* The middle of the occupied cell closest to the point (x, y) in mm, and how far away
+     * it is, if any is within `max_distance` mm
+     */
+    pub fn nearest_obstacle(&self, x: i32, y: i32, max_distance: f64) -> Option<(RobotLocation, f64)> {
+        let from = RobotPose { x, y, angle: Angle::default() };
+
+        self.cells.iter()
+            .filter(|&(_, &evidence)| evidence >= OCCUPIED_EVIDENCE)
+            .map(|(&cell, _)| {
+                let centre = self.cell_centre(cell);
+                (centre, from.distance_to(centre.x, centre.y))
+            })
+            .filter(|&(_, distance)| distance <= max_distance)
+            .min_by(|a, b| a.1.partial_cmp(&b.1).unwrap_or(std::cmp::Ordering::Equal))
+    }
+
+    /* Whether the robot fits along the straight line from `from` to (x, y) in mm, facing
+     * the way it drives, without its body passing over an occupied cell. Unknown cells
+     * count as clear
+     */
+    pub fn path_is_clear(&self, info: &RobotInfo, from: &RobotPose, x: i32, y: i32) -> bool {
+        let distance = from.distance_to(x, y);
+        let (sin, cos) = ((y - from.y) as f64).atan2((x - from.x) as f64).sin_cos();
+        let margin = self.cell_size as f64 / 2.0;
+
+        self.cells.iter()
+            .filter(|&(_, &evidence)| evidence >= OCCUPIED_EVIDENCE)
+            .all(|(&cell, _)| {
+                // From the map's frame to the path's
+                let centre = self.cell_centre(cell);
+                let (dx, dy) = ((centre.x - from.x) as f64, (centre.y - from.y) as f64);
+
+                !info.sweeps(dx * cos + dy * sin, dy * cos - dx * sin, distance, margin)
+            })
+    }
+
+    /* Number of cells seen, and how many of them are occupied
+     */
+    pub fn count(&self) -> (usize, usize) {
+        (self.cells.len(), self.cells.values().filter(|&&evidence| evidence >= OCCUPIED_EVIDENCE).count())
+    }
+}
+
+impl Mapper {
+    pub fn new(grid: OccupancyGrid) -> Mapper {
+        Mapper { grid, last_cell: None }
+    }
+
+    /* Feeds a message from the robot, returning whether the map changed. Positions only
+     * count when the robot has moved on to another cell
+     */
+    pub fn update(&mut self, message: &Message) -> bool {
+        match *message {
+            Message::TofHeightMap(ref map) => {
+                self.grid.add_height_map(map);
+                true
+            },
+            Message::Position(pose) => {
+                let cell = self.grid.cell_at(pose.x, pose.y);
+
+                if self.last_cell == Some(cell) {
+                    return false;
+                }
+
+                self.last_cell = Some(cell);
+                self.grid.add_visit(pose.x, pose.y);
+                true
+            },
+            _ => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::Path;
+
+    /* 3x3 cells of 100mm around the robot at `pose`: a wall ahead, floor elsewhere */
+    fn wall_ahead(pose: RobotPose) -> HeightMap {
+        let (f, w) = (HeightMapCell::Floor, HeightMapCell::Wall);
+
+        HeightMap {
+            xsamps: 3,
+            ysamps: 3,
+            angle: pose.angle,
+            xorig: pose.x,
+            yorig: pose.y,
+            unit_size: 100,
+            cells: vec![f, f, w, f, f, w, f, f, w],
+        }
+    }
+
+    #[test]
+    fn places_height_maps_where_they_were_made() {
+        let mut grid = OccupancyGrid::new(100);
+        let pose = RobotPose { x: 1000, y: 2000, angle: Angle::from_degrees(90.0) };
+
+        grid.add_height_map(&wall_ahead(pose));
+
+        // Facing +y, the wall is 100mm further along y
+        assert_eq!(grid.occupancy(1000, 2100), Occupancy::Occupied);
+        assert_eq!(grid.occupancy(900, 2100), Occupancy::Occupied);
+        assert_eq!(grid.occupancy(1000, 2000), Occupancy::Unknown);
+        assert_eq!(grid.occupancy(1000, 2500), Occupancy::Unknown);
+
+        for _ in 0..2 {
+            grid.add_height_map(&wall_ahead(pose));
+        }
+
+        assert!(grid.is_free(1000, 2000));
+        assert!(grid.is_free(1050, 1950));
+        assert!(!grid.is_free(1000, 2100));
+        assert_eq!(grid.count(), (9, 3));
+    }
+
+    #[test]
+    fn finds_the_nearest_obstacle() {
+        let mut grid = OccupancyGrid::new(100);
+        grid.add_height_map(&wall_ahead(RobotPose { x: 50, y: 50, angle: Angle::default() }));
+
+        assert_eq!(grid.nearest_obstacle(50, 50, 1000.0), Some((RobotLocation { x: 150, y: 50 }, 100.0)));
+        assert_eq!(grid.nearest_obstacle(-500, 50, 500.0), None);
+        assert_eq!(grid.cell_at(-1, 99), (-1, 0));
+    }
+
+    #[test]
+    fn checks_the_robot_fits_along_a_path() {
+        let mut grid = OccupancyGrid::new(100);
+        grid.add_height_map(&wall_ahead(RobotPose { x: 50, y: 50, angle: Angle::default() }));
+
+        let info = RobotInfo { xs: 100, ys: 100, xoffs: 0, yoffs: 0 };
+        let start = RobotPose { x: 50, y: 1050, angle: Angle::default() };
+
+        // The wall covers x 100..200, y -100..200
+        assert!(!grid.path_is_clear(&info, &RobotPose { x: 100, ..start }, 100, -500));
+        assert!(grid.path_is_clear(&info, &start, 50, 500));
+        assert!(grid.path_is_clear(&info, &start, -500, -500));
+        assert!(!grid.path_is_clear(&RobotInfo { ys: 400, ..info }, &start, -50, -500));
+        assert!(grid.path_is_clear(&info, &RobotPose { x: 550, y: 50, angle: Angle::default() }, 1000, 50));
+    }
+
+    #[test]
+    fn maps_from_messages_and_saves() {
+        let mut mapper = Mapper::new(OccupancyGrid::new(100));
+
+        // Where the robot drove is free, however long it stood there
+        let at = |x, y| Message::Position(RobotPose { x, y, angle: Angle::default() });
+        assert!(mapper.update(&at(5000, 5000)));
+        assert!(!mapper.update(&at(5050, 5000)));
+        assert!(mapper.update(&at(5150, 5000)));
+        assert!(mapper.grid.is_free(5000, 5000));
+        assert!(mapper.grid.is_free(5100, 5000));
+        assert_eq!(mapper.grid.count(), (2, 0));
+
+        // Placed by their own origin, whatever position came last
+        assert!(mapper.update(&Message::TofHeightMap(wall_ahead(RobotPose { x: 50, y: 50, angle: Angle::default() }))));
+        assert_eq!(mapper.grid.occupancy(150, 50), Occupancy::Occupied);
+        assert_eq!(mapper.grid.count(), (11, 3));
+
+        let json = serde_json::to_string(&mapper.grid).unwrap();
+        assert!(json.starts_with(r#"{"cell_size":100,"cells":[[-1,-1,-1],"#));
+
+        let path = std::env::temp_dir().join(format!("pulurobot-test-map-{}.json", std::process::id()));
+        let path = path.to_str().unwrap();
+
+        assert!(mapper.grid.save(path).is_ok());
+        assert_eq!(OccupancyGrid::load(path).ok(), Some(mapper.grid.clone()));
+        assert!(!Path::new(&format!("{}.tmp", path)).exists());
+        let _ = fs::remove_file(path);
+
+        assert!(OccupancyGrid::load("/nonexistent/map.json").is_err());
+        assert!(mapper.grid.save("/nonexistent/map.json").is_err());
+    }
+}
@@ -0,0 +1,167 @@
+
+/* Running missions: visiting a sequence of waypoints */
+
+use super::*;
+use std::fs::File;
+use std::io::Read;
+use std::thread;
+use serde_json;
+
+impl Mission {
+    /* Reads a mission file (in json format)
+     */
+    pub fn from_file(mission_path: &str) -> Result<Mission, ConfigError> {
+        let mut mission_file = match File::open(mission_path) {
+            Ok(s) => s,
+            Err(e) => { return Err( ConfigError::from(e).with_path(mission_path) ) }
+        };
+
+        let mut mission_data = String::new();
+
+        if let Err(e) = mission_file.read_to_string(&mut mission_data) {
+            return Err( ConfigError::new(ConfigErrorType::Read).with_path(mission_path).with_source(e) );
+        }
+
+        match serde_json::from_str(&mission_data) {
+            Ok(s) => Ok(s),
+            Err(e) => Err( ConfigError::from(e).with_path(mission_path) )
+        }
+    }
+
+    pub(super) fn default_loops() -> u32 {
+        1
+    }
+
+    pub(super) fn default_tolerance() -> i32 {
+        200
+    }
+
+    pub(super) fn default_step_timeout() -> u64 {
+        300
+    }
+}
+
+/* Runs a mission, reporting progress through `on_event`. Returns Arrived once all laps
+ * are done, or the outcome of the step that failed, after stopping the robot.
+ */
+pub fn run<F: FnMut(&MissionEvent)>(robot: &mut Robot, mission: &Mission, mut on_event: F)
+    -> Result<GotoOutcome, RobotError> {
+
+    // Make sure every waypoint exists before setting off
+    let mut waypoints = Vec::new();
+
+    for step in &mission.steps {
+        match robot.config.get_waypoint(&step.waypoint) {
+            Ok(w) => waypoints.push(w),
+            Err(e) => { return Err( RobotError::from(e) ) }
+        }
+    }
+
+    let step_timeout = Duration::new(mission.step_timeout_secs, 0);
+    let mut lap = 0;
+
+    while !waypoints.is_empty() && (mission.loops == 0 || lap < mission.loops) {
+        lap += 1;
+
+        for (i, (step, waypoint)) in mission.steps.iter().zip(waypoints.iter()).enumerate() {
+            on_event(&MissionEvent::StepStarted { lap, step: i, waypoint: step.waypoint.clone() });
+
+            let outcome = robot.goto_and_wait(waypoint.x, waypoint.y, mission.tolerance, step_timeout)?;
+
+            if outcome != GotoOutcome::Arrived {
+                robot.stop()?;
+                on_event(&MissionEvent::StepFailed { lap, step: i, waypoint: step.waypoint.clone(), outcome });
+                return Ok(outcome);
+            }
+
+            if let Some(heading) = waypoint.heading {
+                robot.turn_to(Angle::from_degrees(heading), step_timeout)?;
+            }
+
+            on_event(&MissionEvent::Arrived { lap, step: i, waypoint: step.waypoint.clone() });
+
+            // Nothing left to wait for after the last stop
+            if mission.loops != lap || i + 1 < mission.steps.len() {
+                thread::sleep(Duration::new(step.dwell_secs, 0));
+            }
+        }
+    }
+
+    on_event(&MissionEvent::Completed { laps: lap });
+
+    Ok(GotoOutcome::Arrived)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Instant;
+
+    fn robot_for(simulator: &Simulator) -> Robot {
+        let mut config = Config::new();
+        config.robot_address = simulator.local_addr().ip().to_string();
+        config.robot_port = simulator.local_addr().port().to_string();
+        config.set_waypoint("a", Waypoint { x: 300, y: 0, heading: None, tags: Vec::new() }).ok();
+        config.set_waypoint("b", Waypoint { x: 0, y: 0, heading: None, tags: Vec::new() }).ok();
+
+        Robot::with_config(config, "").ok().unwrap()
+    }
+
+    fn step(waypoint: &str, dwell_secs: u64) -> MissionStep {
+        MissionStep { waypoint: String::from(waypoint), dwell_secs }
+    }
+
+    fn settings() -> SimulatorSettings {
+        SimulatorSettings { speed: 5000.0, turn_speed: 3600.0, interval_ms: 10, ..SimulatorSettings::default() }
+    }
+
+    #[test]
+    fn runs_laps_against_simulator() {
+        let simulator = Simulator::start("127.0.0.1:0", settings()).ok().unwrap();
+        let mut robot = robot_for(&simulator);
+
+        let mission = Mission { steps: vec![step("a", 1), step("b", 1)], loops: 2, tolerance: 50, step_timeout_secs: 10 };
+        let mut events = Vec::new();
+
+        let outcome = robot.run_mission(&mission, |event| events.push((event.clone(), Instant::now())));
+        assert_eq!(outcome.ok(), Some(GotoOutcome::Arrived));
+
+        let started = |lap, step, waypoint: &str| MissionEvent::StepStarted { lap, step, waypoint: String::from(waypoint) };
+        let arrived = |lap, step, waypoint: &str| MissionEvent::Arrived { lap, step, waypoint: String::from(waypoint) };
+
+        assert_eq!(events.iter().map(|e| e.0.clone()).collect::<Vec<_>>(), vec![
+            started(1, 0, "a"), arrived(1, 0, "a"),
+            started(1, 1, "b"), arrived(1, 1, "b"),
+            started(2, 0, "a"), arrived(2, 0, "a"),
+            started(2, 1, "b"), arrived(2, 1, "b"),
+            MissionEvent::Completed { laps: 2 },
+        ]);
+        assert_eq!(simulator.pose().location(), RobotLocation { x: 0, y: 0 });
+
+        // Dwells at every stop but the last
+        assert!(events[2].1 - events[1].1 >= Duration::new(1, 0));
+        assert!(events[8].1 - events[7].1 < Duration::from_millis(500));
+    }
+
+    #[test]
+    fn stops_at_the_first_failed_step() {
+        let simulator = Simulator::start("127.0.0.1:0", settings()).ok().unwrap();
+        let mut robot = robot_for(&simulator);
+
+        // No time to get anywhere, on a mission which would otherwise go on forever
+        let mission = Mission { steps: vec![step("a", 0), step("b", 0)], loops: 0, tolerance: 50, step_timeout_secs: 0 };
+        let mut events = Vec::new();
+
+        let outcome = robot.run_mission(&mission, |event| events.push(event.clone()));
+        assert_eq!(outcome.ok(), Some(GotoOutcome::TimedOut));
+
+        assert_eq!(events, vec![
+            MissionEvent::StepStarted { lap: 1, step: 0, waypoint: String::from("a") },
+            MissionEvent::StepFailed { lap: 1, step: 0, waypoint: String::from("a"), outcome: GotoOutcome::TimedOut },
+        ]);
+
+        let mission = Mission { steps: vec![step("moon", 0)], loops: 1, tolerance: 50, step_timeout_secs: 10 };
+        let error = robot.run_mission(&mission, |_| panic!("Set off to an unknown waypoint")).err().unwrap();
+        assert_eq!(error.to_string(), "Unknown waypoint: moon");
+    }
+}
@@ -1,35 +1,122 @@
+#[allow(clippy::module_inception)]
 mod pulurobot;
 mod config;
 mod error;
+mod protocol;
+mod link;
+mod pose;
+mod mission;
+mod arrival;
+mod simulator;
+mod capture;
+mod bridge;
+mod auth;
+mod http;
+mod script;
+mod heightmap;
+mod mapping;
+mod footprint;
 
-use std::net::TcpStream;
+pub use self::protocol::decode;
+pub use self::bridge::run_bridge;
+pub use self::http::{serve_http, serve_http_on};
+pub use self::auth::{generate_key, decode_key, sign, verify, auth_message, command_message};
+
+use std::io::{Read, LineWriter};
+use std::error::Error;
+use std::fs::File;
+use std::net::{TcpStream, SocketAddr};
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::AtomicBool;
+use std::thread::JoinHandle;
+use std::sync::mpsc::{Sender, Receiver};
+use std::collections::{BTreeMap, VecDeque};
+use std::time::{Duration, Instant};
 
 
 #[derive(Deserialize, Serialize, Debug)]
 pub struct Config {
     pub name: String,
     pub manufacturer: String,
-    //server_address: String,
-    //server_port: String,
-    //private_key: String,
+    /* Fleet server the bridge connects to, see run_bridge */
+    #[serde(default)]
+    pub server_address: String,
+    #[serde(default)]
+    pub server_port: String,
+    /* Connect to the fleet server over wss:// instead of the unencrypted ws:// */
+    #[serde(default)]
+    pub server_tls: bool,
+    /* Shared with the fleet server, which must sign every command with it */
+    #[serde(default)]
+    pub private_key: String,
     pub robot_address: String,
     pub robot_port: String,
-    pub point_a_x: i32,
-    pub point_a_y: i32,
-    pub point_b_x: i32,
-    pub point_b_y: i32,
+    #[serde(default)]
+    pub waypoints: BTreeMap<String, Waypoint>,
+    #[serde(default)]
+    pub reconnect: ReconnectConfig,
+    /* Points A and B of older config files, moved into `waypoints` when read */
+    #[serde(default, skip_serializing)]
+    point_a_x: Option<i32>,
+    #[serde(default, skip_serializing)]
+    point_a_y: Option<i32>,
+    #[serde(default, skip_serializing)]
+    point_b_x: Option<i32>,
+    #[serde(default, skip_serializing)]
+    point_b_y: Option<i32>,
+}
+
+/* A named location the robot has been taught, stored in the config file */
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
+pub struct Waypoint {
+    pub x: i32,
+    pub y: i32,
+    /* Heading in degrees to turn to after arriving, if any */
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub heading: Option<f64>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub tags: Vec<String>,
+}
+
+/* How to behave when the connection to the robot drops */
+#[derive(Deserialize, Serialize, Debug, Clone)]
+#[serde(default)]
+pub struct ReconnectConfig {
+    /* Delay before the first reconnect attempt, doubled after every failed attempt */
+    pub initial_backoff_ms: u64,
+    pub max_backoff_ms: u64,
+    /* Keep commands sent while disconnected, and send them once reconnected */
+    pub queue_commands: bool,
+    /* Queued commands older than this are dropped instead of sent */
+    pub max_command_age_ms: u64,
+    /* The robot reports its position continuously, so a connection which stays silent
+     * for this long is considered dead and reconnected. 0 waits forever
+     */
+    pub read_timeout_ms: u64,
 }
 
+#[derive(Clone, Copy, Debug, PartialEq)]
 pub enum ConfigErrorType {
     Read,
     Open,
     FileNotFound,
     Deserialization,
     Serialization,
+    WaypointNotFound,
+    WaypointExists,
 }
 
+/* A config, mission, map or capture file which couldn't be used, or an unknown
+ * waypoint. Printed with Display, e.g. "Unknown waypoint: kitchen"
+ */
+#[derive(Debug)]
 pub struct ConfigError {
     pub err_type: ConfigErrorType,
+    pub path: Option<String>,
+    /* Name of the waypoint, for the waypoint errors */
+    pub waypoint: Option<String>,
+    /* What went wrong underneath, e.g. the io or json error */
+    pub source: Option<Box<dyn Error + Send + Sync>>,
 }
 
 pub trait ConfigErrorHandler {
@@ -39,68 +126,688 @@ pub trait ConfigErrorHandler {
 pub trait ConfigHandler {
     fn new() -> Self;
     fn create(config_path: &str) -> Result<Config, ConfigError>;
-    fn from_file(self, config_path: &str) -> Result<Config, ConfigError>; 
+    fn from_file(config_path: &str) -> Result<Config, ConfigError>; 
     fn write(&mut self, config_path: &str) -> Result<(), ConfigError>;  
-    fn set_point(&mut self, name: &str, x: i32, y: i32) -> Result<(), ConfigError>;
-    fn get_point(&mut self, name: &str) -> Result<(i32,i32), ConfigError>;
+    fn set_waypoint(&mut self, name: &str, waypoint: Waypoint) -> Result<(), ConfigError>;
+    fn get_waypoint(&self, name: &str) -> Result<Waypoint, ConfigError>;
+    fn rename_waypoint(&mut self, name: &str, new_name: &str) -> Result<(), ConfigError>;
+    fn delete_waypoint(&mut self, name: &str) -> Result<Waypoint, ConfigError>;
 }
 
+#[derive(Clone, Copy, Debug, PartialEq)]
 pub enum RobotErrorType {
     Connection,
     Write,
     Read,
-    NotYetImplemented
+    Protocol,
+    Timeout,
+    /* No private key configured, or it couldn't be used */
+    Authentication,
+    NotYetImplemented,
+    /* The robot was sent somewhere and waited for, but stopped, got blocked or timed out
+     * on the way
+     */
+    NotArrived,
+    /* A waypoint or file the robot was asked to use is missing or invalid, see the
+     * ConfigError in `source`
+     */
+    Config,
 }
 
+/* Something going wrong talking to the robot or the fleet server. Printed with
+ * Display, e.g. "Unable to connect to 10.0.0.5:22222: Connection refused (os error 111)"
+ */
+#[derive(Debug)]
 pub struct RobotError {
     pub err_type: RobotErrorType,
+    /* Robot or server being talked to */
+    pub address: Option<String>,
+    /* Opcode of the command being sent, or of the offending frame */
+    pub opcode: Option<u8>,
+    /* File being read or written, e.g. a capture */
+    pub path: Option<String>,
+    /* The offending frame, for Protocol errors. Boxed to keep results small */
+    pub protocol: Option<Box<ProtocolError>>,
+    /* What went wrong underneath, e.g. the io error */
+    pub source: Option<Box<dyn Error + Send + Sync>>,
 }
 
 pub trait RobotErrorHandler {
     fn new(error_type: RobotErrorType) -> Self;
 }
 
+#[derive(Clone, Copy, Debug, PartialEq)]
 pub struct RobotLocation {
     pub x: i32,
     pub y: i32,
 }
 
+/* A heading, stored the way the robot sends it: as a signed fraction of a full turn,
+ * where 65536 is 360 degrees. Angles grow counter-clockwise.
+ */
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Angle {
+    raw: i16,
+}
+
+/* Position in mm and heading of the robot, reported in the 130 message */
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct RobotPose {
+    pub x: i32,
+    pub y: i32,
+    pub angle: Angle,
+}
+
+/* Battery status reported by the robot in the 134 message */
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+pub struct BatteryStatus {
+    pub charging: bool,
+    /* Charging has finished, the battery is full */
+    pub finished: bool,
+    /* Battery voltage in mV */
+    pub voltage: u16,
+    pub percentage: u8,
+}
+
+/* Called from the reader thread when the battery drops below a threshold */
+pub type BatteryCallback = Box<dyn Fn(&BatteryStatus) + Send>;
+
+/* A low-battery callback, armed until the battery drops below its threshold, and
+ * re-armed once it has been charged above it again
+ */
+struct BatteryAlarm {
+    threshold: u8,
+    callback: BatteryCallback,
+    triggered: bool,
+}
+
+/* State reported by the robot in the 139 message */
+#[derive(Clone, Copy, Debug, PartialEq)]
 pub enum RobotState {
-    Undef = -1,
-	Idle = 0,
-	Think = 1,
-	Fwd = 2,
-	Rev = 3,
-	Left = 4,
-	Right = 5,
-	Charging = 6,
-    Daijuing = 7
+    Undef,
+    Idle,
+    Think,
+    Fwd,
+    Rev,
+    Left,
+    Right,
+    Charging,
+    Daijuing,
+    /* A state number this library doesn't know about */
+    Unknown(u8),
+}
+
+/* Every frame on the robot's TCP stream is laid out as [opcode][u16 len][payload],
+ * with all integers in big-endian byte order.
+ */
+pub const FRAME_HEADER_LEN: usize = 3;
+
+pub const OPCODE_POSITION: u8 = 130;
+pub const OPCODE_BATTERY: u8 = 134;
+pub const OPCODE_TOF_HMAP: u8 = 138;
+pub const OPCODE_STATE: u8 = 139;
+pub const OPCODE_ROBOT_INFO: u8 = 140;
+
+pub const OPCODE_DEST: u8 = 55;
+pub const OPCODE_ROUTE: u8 = 56;
+pub const OPCODE_CHARGE: u8 = 57;
+pub const OPCODE_MODE: u8 = 58;
+pub const OPCODE_MANUAL: u8 = 59;
+pub const OPCODE_SPEED_LIMIT: u8 = 63;
+
+/* Mode numbers understood by the 58 command */
+pub const MODE_LOCALIZE: u8 = 3;
+pub const MODE_FREE: u8 = 5;
+pub const MODE_STOP: u8 = 8;
+
+/* Why a frame couldn't be decoded */
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ProtocolErrorKind {
+    /* The frame ends before the payload length its header claims */
+    Truncated,
+    /* The payload length isn't one the opcode is sent with */
+    BadLength,
+    /* A value in the payload is out of range, e.g. a manual direction */
+    BadValue,
+    /* Not an opcode this side of the connection understands */
+    UnknownOpcode,
+    /* Bytes skipped while looking for the start of the next frame. `length` is the
+     * number of bytes skipped
+     */
+    Garbage,
+}
+
+/* A frame which couldn't be decoded: its opcode, the payload length its header claims,
+ * and the bytes received, header included and cut to the first PROTOCOL_ERROR_BYTES
+ */
+#[derive(Clone, Debug, PartialEq)]
+pub struct ProtocolError {
+    pub kind: ProtocolErrorKind,
+    pub opcode: u8,
+    pub length: usize,
+    pub bytes: Vec<u8>,
+}
+
+pub const PROTOCOL_ERROR_BYTES: usize = 32;
+
+/* What the reader thread has received since the robot was created, see
+ * PuluRobot::get_protocol_stats
+ */
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ProtocolStats {
+    /* Frames decoded, unknown opcodes included */
+    pub frames: u64,
+    /* Frames which couldn't be decoded, and runs of garbage skipped */
+    pub errors: u64,
+    pub skipped_bytes: u64,
+    /* Number of frames received by opcode, for opcodes this library doesn't decode */
+    pub unknown_opcodes: BTreeMap<u8, u64>,
+    pub last_error: Option<ProtocolError>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Frame {
+    pub opcode: u8,
+    pub payload: Vec<u8>,
+}
+
+/* A decoded message sent by the robot, one variant per known opcode */
+#[derive(Clone, Debug, PartialEq)]
+pub enum Message {
+    /* 130: Position and heading */
+    Position(RobotPose),
+    /* 134: Battery status */
+    Battery(BatteryStatus),
+    /* 138: 3D TOF height map */
+    TofHeightMap(HeightMap),
+    /* 139: Current state of the robot */
+    State(RobotState),
+    /* 140: Size of the robot, sent once when a client connects */
+    RobotInfo(RobotInfo),
+    /* Any opcode we don't know how to decode yet */
+    Unknown { opcode: u8, payload: Vec<u8> },
+}
+
+/* What the 3D TOF sensors saw in a cell of a height map, from the robot's point of
+ * view. Sent as a single byte.
+ */
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum HeightMapCell {
+    /* Not seen by any sensor */
+    Unseen,
+    Floor,
+    /* A bump the robot can drive over, e.g. a door sill */
+    Threshold,
+    SmallDrop,
+    SmallItem,
+    /* E.g. stairs going down */
+    BigDrop,
+    /* Something the robot doesn't fit under */
+    LowCeiling,
+    BigItem,
+    Wall,
+    /* A cell value this library doesn't know about */
+    Unknown(u8),
+}
+
+/* 138: A grid of what the 3D TOF sensors see around the robot, laid out in the robot's
+ * frame: x pointing ahead and y to the left, with the robot in the middle cell. Sent as
+ * [xsamps u16][ysamps u16][angle i16][xorig i32][yorig i32][unit_size u8][cells..]
+ */
+#[derive(Clone, Debug, PartialEq)]
+pub struct HeightMap {
+    pub xsamps: u16,
+    pub ysamps: u16,
+    /* Heading of the robot when the map was made */
+    pub angle: Angle,
+    /* Position of the robot in mm when the map was made */
+    pub xorig: i32,
+    pub yorig: i32,
+    /* Size of a cell in mm */
+    pub unit_size: u8,
+    /* Row by row from y = 0, xsamps cells each */
+    pub cells: Vec<HeightMapCell>,
+}
+
+/* 140: The robot's footprint, a rectangle `xs` mm along its heading and `ys` mm across,
+ * whose middle is `xoffs` mm ahead of and `yoffs` mm left of the point positions are
+ * reported for (the point the robot turns around). Sent as [xs i16][ys i16][xoffs i16]
+ * [yoffs i16]. Only ever seen sent once, right after a client connects.
+ *
+ * The meaning is our reading of the values, which fit the robot's body: 480x400mm, its
+ * middle 120mm behind the drive wheels. The firmware doesn't document it.
+ */
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct RobotInfo {
+    pub xs: i16,
+    pub ys: i16,
+    pub xoffs: i16,
+    pub yoffs: i16,
+}
+
+/* What an occupancy grid knows about a cell */
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Occupancy {
+    Unknown,
+    Free,
+    Occupied,
+}
+
+/* A map of the site, built from height maps: squares of `cell_size` mm along the axes
+ * of the robot's coordinate system, each holding evidence of being occupied (positive)
+ * or free (negative). Saved as json, see OccupancyGrid::save.
+ */
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct OccupancyGrid {
+    pub cell_size: i32,
+    /* Only cells seen at least once, by column and row */
+    #[serde(with = "self::mapping::cells")]
+    cells: BTreeMap<(i32, i32), i8>,
+}
+
+/* Builds an occupancy grid from the messages of a robot, placing every height map at
+ * the position it was made at, and counting the cells the robot drives over as free
+ */
+pub struct Mapper {
+    pub grid: OccupancyGrid,
+    /* Cell of the last position reported, so standing still counts only once */
+    last_cell: Option<(i32, i32)>,
+}
+
+/* A decoded message as printed by `listen --json`: when it arrived, its opcode, and
+ * the fields of the message tagged by "type"
+ */
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct MessageRecord {
+    /* Milliseconds since the Unix epoch */
+    pub time_ms: u64,
+    pub opcode: u8,
+    #[serde(flatten)]
+    pub message: Message,
+}
+
+/* Direction of a single manual drive step (opcode 59) */
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ManualDirection {
+    Forward = 10,
+    Backward = 11,
+    Left = 12,
+    Right = 13,
+}
+
+/* A command accepted by the robot, see Command::encode for the wire format */
+#[derive(Clone, Debug, PartialEq)]
+pub enum Command {
+    /* 55: Drive in a straight line to (x, y) without route planning, optionally in reverse */
+    Dest { x: i32, y: i32, backwards: bool },
+    /* 56: Plan a route to (x, y) and follow it */
+    Route { x: i32, y: i32 },
+    /* 57: Find the charger and dock to it */
+    Charge,
+    /* 58 with MODE_LOCALIZE: Reset the robot's internal coordinate system */
+    Localize,
+    /* 58 with MODE_FREE: Unblock the wheels */
+    Free,
+    /* 58 with MODE_STOP: Stop whatever the robot is currently doing */
+    Stop,
+    /* 58: Any other mode number */
+    Mode(u8),
+    /* 59: Take a single small step in the given direction */
+    Manual(ManualDirection),
+    /* 63: Limit the top speed of the robot */
+    SpeedLimit(u8),
+}
+
+/* Reads frames one at a time from a byte stream, e.g. the robot's TcpStream, skipping
+ * garbage until it finds something that looks like a frame again
+ */
+pub struct FrameReader<R: Read> {
+    reader: R,
+    /* Bytes read but not yet returned in a frame */
+    pending: Vec<u8>,
+    /* Bytes skipped as garbage before the last frame or Garbage error */
+    skipped: Vec<u8>,
+}
+
+/* The latest values received from the robot, kept up to date by the reader thread */
+#[derive(Clone, Debug, Default)]
+pub struct Telemetry {
+    pub pose: Option<RobotPose>,
+    pub battery: Option<BatteryStatus>,
+    pub state: Option<RobotState>,
+    pub height_map: Option<HeightMap>,
+    pub robot_info: Option<RobotInfo>,
+    pub protocol: ProtocolStats,
+}
+
+/* Changes of the connection to the robot, as reported to link subscribers */
+#[derive(Clone, Debug, PartialEq)]
+pub enum LinkState {
+    Connected,
+    Disconnected,
+    /* Waiting `delay` before making reconnect attempt number `attempt` */
+    Retrying { attempt: u32, delay: Duration },
 }
 
+/* Connection state shared between a Robot and its supervisor thread */
+struct Link {
+    /* Write half of the connection, None while disconnected */
+    stream: Option<TcpStream>,
+    /* Commands waiting for the connection to come back, with the time they were sent */
+    queue: VecDeque<(Instant, Command)>,
+    /* Bumped on every connect and disconnect, telling older supervisors to exit */
+    generation: u64,
+    /* Where frames going either way are recorded, if capturing */
+    capture: Option<CaptureWriter>,
+    /* When the last command went out, to tell states reported before it had an effect */
+    sent_at: Option<Instant>,
+}
+
+/* A robot with one long-lived connection. A supervisor thread reads from the connection,
+ * updates `telemetry` and fans out every decoded message to the subscribers. When the
+ * connection drops, it reconnects with exponential backoff.
+ */
 pub struct Robot {
-    pub stream: TcpStream,
     pub config_path: String,
     pub config: Config,
+    pub telemetry: Arc<Mutex<Telemetry>>,
+    link: Arc<Mutex<Link>>,
+    subscribers: Arc<Mutex<Vec<Sender<Message>>>>,
+    link_subscribers: Arc<Mutex<Vec<Sender<LinkState>>>>,
+    battery_alarms: Arc<Mutex<Vec<BatteryAlarm>>>,
+}
+
+/* How a drive towards a target ended */
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum GotoOutcome {
+    /* Idle within the tolerance of the target */
+    Arrived,
+    /* Still on the way when the timeout ran out */
+    TimedOut,
+    /* Became idle, or never started moving, away from the target */
+    Stopped,
+    /* Busy, but without making any progress for a while */
+    Blocked,
+}
+
+/* A stop on a mission: a waypoint from the config file, and how long to stay there */
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
+pub struct MissionStep {
+    pub waypoint: String,
+    #[serde(default)]
+    pub dwell_secs: u64,
+}
+
+/* An ordered list of waypoints to visit, e.g. a patrol or delivery route */
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
+pub struct Mission {
+    pub steps: Vec<MissionStep>,
+    /* Number of times to run through the steps, 0 meaning forever */
+    #[serde(default = "Mission::default_loops")]
+    pub loops: u32,
+    /* How close in mm the robot must get to a waypoint */
+    #[serde(default = "Mission::default_tolerance")]
+    pub tolerance: i32,
+    /* How long the robot may take to reach a single waypoint */
+    #[serde(default = "Mission::default_step_timeout")]
+    pub step_timeout_secs: u64,
+}
+
+/* Progress of a running mission. `lap` counts from 1, `step` from 0 */
+#[derive(Clone, Debug, PartialEq)]
+pub enum MissionEvent {
+    StepStarted { lap: u32, step: usize, waypoint: String },
+    Arrived { lap: u32, step: usize, waypoint: String },
+    StepFailed { lap: u32, step: usize, waypoint: String, outcome: GotoOutcome },
+    Completed { laps: u32 },
+}
+
+/* Where to send the robot: a waypoint from the config file, or coordinates in mm */
+#[derive(Clone, Debug, PartialEq)]
+pub enum GotoTarget {
+    Waypoint(String),
+    Position(i32, i32),
+}
+
+/* How a script condition compares the robot's battery level or state */
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Comparison {
+    Less,
+    LessOrEqual,
+    Greater,
+    GreaterOrEqual,
+    Equal,
+    NotEqual,
+}
+
+/* What an `if` of a script checks, e.g. `battery < 20` or `state == idle` */
+#[derive(Clone, Debug, PartialEq)]
+pub enum Condition {
+    /* Battery level in percent */
+    Battery(Comparison, u8),
+    /* Only Equal and NotEqual are allowed */
+    State(Comparison, RobotState),
+}
+
+/* A line of a script, or a block of lines */
+#[derive(Clone, Debug, PartialEq)]
+pub enum Statement {
+    /* Any other command, run by whoever runs the script, e.g. `localize` */
+    Command(Vec<String>),
+    /* Routes the robot, without waiting for it to get there */
+    Goto(GotoTarget),
+    /* Waits for the robot to reach the target of the last goto */
+    WaitArrival { timeout: Duration },
+    WaitState { state: RobotState, timeout: Duration },
+    Sleep(Duration),
+    /* Runs the body `count` times, 0 meaning forever */
+    Repeat { count: u32, body: Vec<ScriptLine> },
+    If { condition: Condition, then: Vec<ScriptLine>, otherwise: Vec<ScriptLine> },
+}
+
+/* A statement, and where it is written in the script */
+#[derive(Clone, Debug, PartialEq)]
+pub struct ScriptLine {
+    /* Counting from 1 */
+    pub line: usize,
+    /* The line as written, without indentation */
+    pub text: String,
+    pub statement: Statement,
+}
+
+/* Console commands to run one after the other, with waits, loops and conditions.
+ * See config/script.sample for the syntax.
+ */
+#[derive(Clone, Debug, PartialEq)]
+pub struct Script {
+    pub lines: Vec<ScriptLine>,
+}
+
+/* A script that can't be read or parsed, or a step of it that failed */
+#[derive(Clone, Debug, PartialEq)]
+pub struct ScriptError {
+    /* Counting from 1, or 0 if the script couldn't be read at all */
+    pub line: usize,
+    pub message: String,
+}
+
+/* The result of a step of a running script, with a short note on success, e.g.
+ * "arrived", or why it failed
+ */
+#[derive(Clone, Debug, PartialEq)]
+pub struct ScriptStep {
+    pub line: usize,
+    pub text: String,
+    pub result: Result<String, String>,
+}
+
+/* A command sent by the fleet server, as json tagged by "command", e.g.
+ * {"command": "goto", "x": 100, "y": -200}. Sent as the payload of a signed
+ * ServerRequest::Command
+ */
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+#[serde(tag = "command", rename_all = "snake_case")]
+pub enum ServerCommand {
+    Goto { x: i32, y: i32 },
+    GotoWaypoint { name: String },
+    Stop,
+    Free,
+    Localize,
+    SaveWaypoint {
+        name: String,
+        #[serde(default)]
+        tags: Vec<String>,
+        #[serde(default)]
+        heading: bool,
+    },
+}
+
+/* What the fleet server sends, as json tagged by "type": a challenge for the robot to
+ * prove it holds the private key, or a command signed with it. `payload` is the json of
+ * a ServerCommand, and `seq` must grow with every command of a session.
+ */
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ServerRequest {
+    Challenge { nonce: String },
+    Command { seq: u64, payload: String, signature: String },
+}
+
+/* Telemetry and replies sent to the fleet server, as json tagged by "type" */
+#[derive(Clone, Debug, PartialEq, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ServerEvent {
+    /* Angle in degrees */
+    Pose { x: i32, y: i32, angle: f64 },
+    Battery { charging: bool, finished: bool, voltage: u16, percentage: u8 },
+    State { state: String },
+    RobotInfo { xs: i16, ys: i16, xoffs: i16, yoffs: i16 },
+    /* Whether the bridge is connected to the robot */
+    Link { connected: bool },
+    /* Answer to a challenge, opening a session which commands are signed for */
+    Auth { robot: String, session: String, signature: String },
+    /* Answer to every command received */
+    Reply {
+        ok: bool,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        error: Option<String>,
+    },
+}
+
+/* Which way a captured frame went */
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum CaptureDirection {
+    /* From the robot */
+    Received,
+    /* To the robot */
+    Sent,
+}
+
+/* One line of a capture file: a raw frame, and when it went by */
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct CaptureRecord {
+    /* Milliseconds since the capture was started */
+    pub time_ms: u64,
+    pub direction: CaptureDirection,
+    /* The whole frame, header included. Written as hex */
+    #[serde(with = "self::capture::hex")]
+    pub frame: Vec<u8>,
+}
+
+/* An open capture file, written one json line per frame */
+struct CaptureWriter {
+    writer: LineWriter<File>,
+    started: Instant,
+}
+
+/* Serves the received frames of a capture over TCP, like the robot would */
+pub struct Replayer {
+    addr: SocketAddr,
+    running: Arc<AtomicBool>,
+    thread: Option<JoinHandle<()>>,
+}
+
+/* Behaviour of the simulated robot */
+#[derive(Clone, Debug)]
+pub struct SimulatorSettings {
+    /* Driving speed in mm/s */
+    pub speed: f64,
+    /* Turning speed in degrees/s */
+    pub turn_speed: f64,
+    /* Time between telemetry broadcasts, which is also the simulation step */
+    pub interval_ms: u64,
+    /* Where the robot starts out */
+    pub start: RobotPose,
+}
+
+/* The virtual robot itself, moved by the simulation thread */
+struct SimulatedRobot {
+    x: f64,
+    y: f64,
+    /* Heading in degrees */
+    angle: f64,
+    state: RobotState,
+    target: Option<(f64, f64)>,
+    /* Drive to the target backwards, as asked by a Dest command */
+    reverse: bool,
+    /* Battery level in percent */
+    battery: f64,
+    charging: bool,
+}
+
+/* A virtual robot listening on a TCP port, which accepts the same commands as the real
+ * robot and broadcasts position, battery, state, height map and robot info messages
+ */
+pub struct Simulator {
+    addr: SocketAddr,
+    robot: Arc<Mutex<SimulatedRobot>>,
+    running: Arc<AtomicBool>,
+    thread: Option<JoinHandle<()>>,
 }
 
 pub trait PuluRobot {
     fn from_config(config_path: &str) -> Result<Robot, RobotError>;
+    fn with_config(config: Config, config_path: &str) -> Result<Robot, RobotError>;
     fn connect(&mut self) -> Result<(), RobotError>;
     fn disconnect(&mut self);
     fn get_location(&mut self) -> Result<RobotLocation, RobotError>;
-    fn get_state() -> Result<RobotState, RobotError>;
+    fn get_pose(&mut self) -> Result<RobotPose, RobotError>;
+    fn get_state(&mut self) -> Result<RobotState, RobotError>;
+    fn get_battery(&mut self) -> Result<BatteryStatus, RobotError>;
+    fn get_height_map(&mut self) -> Result<HeightMap, RobotError>;
+    fn get_robot_info(&mut self) -> Result<RobotInfo, RobotError>;
+    fn obstacle_ahead(&mut self, distance: i32) -> Result<Option<f64>, RobotError>;
+    fn get_protocol_stats(&self) -> ProtocolStats;
+    fn on_low_battery(&mut self, threshold: u8, callback: BatteryCallback);
+    fn wait_for_state(&mut self, state: RobotState, timeout: Duration) -> Result<(), RobotError>;
     fn free(&mut self) -> Result<(), RobotError>;
-    fn goto_point(&mut self, point: &str) -> Result<(), RobotError>;
+    fn goto_waypoint(&mut self, name: &str) -> Result<(), RobotError>;
     fn goto(&mut self, x: i32, y: i32) -> Result<(), RobotError>;
+    fn forward(&mut self, distance: i32) -> Result<(), RobotError>;
+    fn move_by(&mut self, dx: i32, dy: i32) -> Result<(), RobotError>;
+    fn turn(&mut self, degrees: f64, timeout: Duration) -> Result<(), RobotError>;
+    fn goto_and_wait(&mut self, x: i32, y: i32, tolerance: i32, timeout: Duration) -> Result<GotoOutcome, RobotError>;
+    fn goto_with_heading(&mut self, x: i32, y: i32, heading: Angle, timeout: Duration) -> Result<GotoOutcome, RobotError>;
+    fn turn_to(&mut self, heading: Angle, timeout: Duration) -> Result<(), RobotError>;
     fn localize(&mut self) -> Result<(), RobotError>;  
     fn stop(&mut self) -> Result<(), RobotError>;
-    fn save_location(&mut self, location: &str) -> Result<(), RobotError>;
+    fn save_waypoint(&mut self, name: &str, tags: Vec<String>, with_heading: bool) -> Result<(), RobotError>;
+    fn send(&mut self, command: &Command) -> Result<(), RobotError>;
+    fn subscribe(&mut self) -> Receiver<Message>;
+    fn subscribe_link(&mut self) -> Receiver<LinkState>;
+    fn start_capture(&mut self, capture_path: &str) -> Result<(), RobotError>;
+    fn stop_capture(&mut self);
+    fn run_mission<F: FnMut(&MissionEvent)>(&mut self, mission: &Mission, on_event: F) -> Result<GotoOutcome, RobotError>;
+    fn run_script<C, F>(&mut self, script: &Script, command: C, on_step: F) -> Result<(), ScriptError>
+        where C: FnMut(&mut Robot, &[String]) -> Result<(), String>, F: FnMut(&ScriptStep);
 }
 
-impl From<i8> for RobotState {
-    fn from(t:i8) -> RobotState {
+/* The state number is sent as a single byte, with Undef being -1 */
+impl From<u8> for RobotState {
+    fn from(t:u8) -> RobotState {
         match t {
-            -1 => RobotState::Undef,
+            255 => RobotState::Undef,
             0 => RobotState::Idle,
             1 => RobotState::Think,
             2 => RobotState::Fwd,
@@ -109,10 +816,24 @@ impl From<i8> for RobotState {
             5 => RobotState::Right,
             6 => RobotState::Charging,
             7 => RobotState::Daijuing,
-            _ => panic!("Could not find RobotState")
+            n => RobotState::Unknown(n),
         }
     }
 }
 
-
-
+impl From<RobotState> for u8 {
+    fn from(state: RobotState) -> u8 {
+        match state {
+            RobotState::Undef => 255,
+            RobotState::Idle => 0,
+            RobotState::Think => 1,
+            RobotState::Fwd => 2,
+            RobotState::Rev => 3,
+            RobotState::Left => 4,
+            RobotState::Right => 5,
+            RobotState::Charging => 6,
+            RobotState::Daijuing => 7,
+            RobotState::Unknown(n) => n,
+        }
+    }
+}
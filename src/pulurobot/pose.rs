@@ -0,0 +1,90 @@
+
+/* Conversions between the robot's raw angles, degrees and radians */
+
+use super::{Angle, RobotPose, RobotLocation};
+
+use std::f64::consts::PI;
+
+/* Raw units in a full turn */
+const FULL_TURN: f64 = 65536.0;
+
+impl Angle {
+    pub fn from_raw(raw: i16) -> Self {
+        Angle { raw }
+    }
+
+    /* Any number of degrees, wrapped into [-180, 180)
+     */
+    pub fn from_degrees(degrees: f64) -> Self {
+        Angle::from_turns(degrees / 360.0)
+    }
+
+    /* Any number of radians, wrapped into [-pi, pi)
+     */
+    pub fn from_radians(radians: f64) -> Self {
+        Angle::from_turns(radians / (2.0 * PI))
+    }
+
+    fn from_turns(turns: f64) -> Self {
+        let raw = (turns * FULL_TURN).round() as i64;
+        Angle { raw: raw as i16 }
+    }
+
+    pub fn raw(&self) -> i16 {
+        self.raw
+    }
+
+    /* In the range [-180, 180)
+     */
+    pub fn degrees(&self) -> f64 {
+        self.raw as f64 / FULL_TURN * 360.0
+    }
+
+    /* In the range [-pi, pi)
+     */
+    pub fn radians(&self) -> f64 {
+        self.raw as f64 / FULL_TURN * 2.0 * PI
+    }
+
+    /* The shortest turn from `from` to this angle, positive being counter-clockwise
+     */
+    pub fn difference(&self, from: Angle) -> Angle {
+        Angle { raw: self.raw.wrapping_sub(from.raw) }
+    }
+}
+
+impl RobotPose {
+    pub fn location(&self) -> RobotLocation {
+        RobotLocation { x: self.x, y: self.y }
+    }
+
+    /* Straight-line distance in mm to (x, y)
+     */
+    pub fn distance_to(&self, x: i32, y: i32) -> f64 {
+        let dx = (x as i64 - self.x as i64) as f64;
+        let dy = (y as i64 - self.y as i64) as f64;
+
+        dx.hypot(dy)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn converts_between_units() {
+        assert_eq!(Angle::from_degrees(90.0).raw(), 16384);
+        assert_eq!(Angle::from_radians(-PI / 2.0).raw(), -16384);
+        assert_eq!(Angle::from_raw(-32768).degrees(), -180.0);
+        assert!((Angle::from_degrees(45.0).radians() - PI / 4.0).abs() < 1e-9);
+    }
+
+    #[test]
+    fn wraps_around() {
+        assert_eq!(Angle::from_degrees(450.0), Angle::from_degrees(90.0));
+        assert_eq!(Angle::from_degrees(180.0), Angle::from_degrees(-180.0));
+        assert_eq!(Angle::from_degrees(-10.0).difference(Angle::from_degrees(170.0)).degrees(), -180.0);
+        assert!((Angle::from_degrees(-170.0).difference(Angle::from_degrees(170.0)).degrees() - 20.0).abs() < 0.01);
+    }
+}
@@ -0,0 +1,671 @@
+
+/* Encoding and decoding of the frames exchanged with the robot over TCP */
+
+use super::*;
+
+use std::io::Read;
+use std::time::{SystemTime, UNIX_EPOCH};
+use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+use serde::{Serialize, Serializer};
+use serde::ser::SerializeMap;
+use super::capture::hex;
+
+/* Garbage is returned as an error at least every this many bytes, so that a stream of
+ * nothing but garbage doesn't pile up in memory
+ */
+const MAX_GARBAGE_LEN: usize = 64 * 1024;
+
+/* Decodes a single complete frame, header included, into a Message
+ */
+pub fn decode(buf: &[u8]) -> Result<Message, ProtocolError> {
+    let (opcode, payload) = split_frame(buf)?;
+
+    Message::from_payload(opcode, payload)
+}
+
+/* Opcode and payload of a complete frame, header included
+ */
+fn split_frame(buf: &[u8]) -> Result<(u8, &[u8]), ProtocolError> {
+    if buf.len() < FRAME_HEADER_LEN {
+        return Err( ProtocolError::new(ProtocolErrorKind::Truncated, buf.first().cloned().unwrap_or(0), &[]) );
+    }
+
+    let len = ((buf[1] as usize) << 8) | (buf[2] as usize);
+
+    if buf.len() < FRAME_HEADER_LEN + len {
+        let mut error = ProtocolError::new(ProtocolErrorKind::Truncated, buf[0], &buf[FRAME_HEADER_LEN..]);
+        error.length = len;
+        return Err(error);
+    }
+
+    Ok((buf[0], &buf[FRAME_HEADER_LEN..FRAME_HEADER_LEN + len]))
+}
+
+/* Smallest and largest payload each opcode is sent with, going either way. None for
+ * opcodes this library doesn't know
+ */
+pub(super) fn payload_len(opcode: u8) -> Option<(usize, usize)> {
+    match opcode {
+        OPCODE_POSITION => Some((10, 10)),
+        OPCODE_BATTERY => Some((4, 4)),
+        OPCODE_TOF_HMAP => Some((super::heightmap::HEADER_LEN, u16::MAX as usize)),
+        OPCODE_STATE => Some((1, 1)),
+        OPCODE_ROBOT_INFO => Some((8, 8)),
+        OPCODE_DEST => Some((9, 9)),
+        // Sent with a trailing zero
+        OPCODE_ROUTE => Some((8, 9)),
+        OPCODE_CHARGE => Some((0, 1)),
+        OPCODE_MODE | OPCODE_MANUAL | OPCODE_SPEED_LIMIT => Some((1, 1)),
+        _ => None,
+    }
+}
+
+/* Prepends the frame header to a payload
+ */
+fn frame(opcode: u8, payload: Vec<u8>) -> Vec<u8> {
+    let mut buf = Vec::with_capacity(FRAME_HEADER_LEN + payload.len());
+    buf.push(opcode);
+    buf.write_u16::<BigEndian>(payload.len() as u16).unwrap();
+    buf.extend(payload);
+
+    buf
+}
+
+/* Fails unless the payload length is one the opcode is sent with. Unknown opcodes
+ * may be any length
+ */
+fn check_len(opcode: u8, payload: &[u8]) -> Result<(), ProtocolError> {
+    match payload_len(opcode) {
+        Some((min, max)) if payload.len() < min || payload.len() > max => {
+            Err( ProtocolError::new(ProtocolErrorKind::BadLength, opcode, payload) )
+        },
+        _ => Ok(()),
+    }
+}
+
+impl Message {
+    /* Decodes the payload of a frame with the given opcode
+     */
+    pub fn from_payload(opcode: u8, mut payload: &[u8]) -> Result<Message, ProtocolError> {
+        check_len(opcode, payload)?;
+
+        let message = match opcode {
+            OPCODE_POSITION => {
+                let angle = Angle::from_raw(payload.read_i16::<BigEndian>().unwrap());
+
+                Message::Position(RobotPose {
+                    x: payload.read_i32::<BigEndian>().unwrap(),
+                    y: payload.read_i32::<BigEndian>().unwrap(),
+                    angle,
+                })
+            },
+            OPCODE_BATTERY => {
+                Message::Battery(BatteryStatus {
+                    charging: payload[0] & 1 != 0,
+                    finished: payload[0] & 2 != 0,
+                    voltage: (&payload[1..3]).read_u16::<BigEndian>().unwrap(),
+                    percentage: payload[3],
+                })
+            },
+            OPCODE_TOF_HMAP => Message::TofHeightMap(HeightMap::from_payload(payload)?),
+            OPCODE_STATE => {
+                Message::State(RobotState::from(payload[0]))
+            },
+            OPCODE_ROBOT_INFO => {
+                Message::RobotInfo(RobotInfo {
+                    xs: payload.read_i16::<BigEndian>().unwrap(),
+                    ys: payload.read_i16::<BigEndian>().unwrap(),
+                    xoffs: payload.read_i16::<BigEndian>().unwrap(),
+                    yoffs: payload.read_i16::<BigEndian>().unwrap(),
+                })
+            },
+            _ => Message::Unknown { opcode, payload: payload.to_vec() },
+        };
+
+        Ok(message)
+    }
+
+    /* Produces the complete wire frame for the message, header included, as the robot
+     * would send it. Inverse of decode
+     */
+    pub fn encode(&self) -> Vec<u8> {
+        let mut payload: Vec<u8> = Vec::new();
+
+        match *self {
+            Message::Position(pose) => {
+                payload.write_i16::<BigEndian>(pose.angle.raw()).unwrap();
+                payload.write_i32::<BigEndian>(pose.x).unwrap();
+                payload.write_i32::<BigEndian>(pose.y).unwrap();
+            },
+            Message::Battery(battery) => {
+                payload.push((battery.charging as u8) | (battery.finished as u8) << 1);
+                payload.write_u16::<BigEndian>(battery.voltage).unwrap();
+                payload.push(battery.percentage);
+            },
+            Message::TofHeightMap(ref map) => map.write_payload(&mut payload),
+            Message::State(state) => payload.push(u8::from(state)),
+            Message::RobotInfo(info) => {
+                payload.write_i16::<BigEndian>(info.xs).unwrap();
+                payload.write_i16::<BigEndian>(info.ys).unwrap();
+                payload.write_i16::<BigEndian>(info.xoffs).unwrap();
+                payload.write_i16::<BigEndian>(info.yoffs).unwrap();
+            },
+            Message::Unknown { payload: ref data, .. } => payload.extend(data),
+        }
+
+        frame(self.opcode(), payload)
+    }
+
+    /* The opcode this message was received with
+     */
+    pub fn opcode(&self) -> u8 {
+        match *self {
+            Message::Position(_) => OPCODE_POSITION,
+            Message::Battery(_) => OPCODE_BATTERY,
+            Message::TofHeightMap(_) => OPCODE_TOF_HMAP,
+            Message::State(_) => OPCODE_STATE,
+            Message::RobotInfo(_) => OPCODE_ROBOT_INFO,
+            Message::Unknown { opcode, .. } => opcode,
+        }
+    }
+}
+
+/* Messages are written as json tagged by "type", with angles in degrees, and raw
+ * payloads and height map cells in hex
+ */
+impl Serialize for Message {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut map = serializer.serialize_map(None)?;
+
+        match *self {
+            Message::Position(pose) => {
+                map.serialize_entry("type", "position")?;
+                map.serialize_entry("x", &pose.x)?;
+                map.serialize_entry("y", &pose.y)?;
+                map.serialize_entry("angle", &pose.angle.degrees())?;
+            },
+            Message::Battery(battery) => {
+                map.serialize_entry("type", "battery")?;
+                map.serialize_entry("charging", &battery.charging)?;
+                map.serialize_entry("finished", &battery.finished)?;
+                map.serialize_entry("voltage", &battery.voltage)?;
+                map.serialize_entry("percentage", &battery.percentage)?;
+            },
+            Message::TofHeightMap(ref hmap) => {
+                map.serialize_entry("type", "tof_height_map")?;
+                map.serialize_entry("xsamps", &hmap.xsamps)?;
+                map.serialize_entry("ysamps", &hmap.ysamps)?;
+                map.serialize_entry("angle", &hmap.angle.degrees())?;
+                map.serialize_entry("xorig", &hmap.xorig)?;
+                map.serialize_entry("yorig", &hmap.yorig)?;
+                map.serialize_entry("unit_size", &hmap.unit_size)?;
+                let cells: Vec<u8> = hmap.cells.iter().map(|&cell| u8::from(cell)).collect();
+                map.serialize_entry("cells", &hex::encode(&cells))?;
+            },
+            Message::State(state) => {
+                map.serialize_entry("type", "state")?;
+                map.serialize_entry("state", &format!("{:?}", state))?;
+                map.serialize_entry("code", &u8::from(state))?;
+            },
+            Message::RobotInfo(info) => {
+                map.serialize_entry("type", "robot_info")?;
+                map.serialize_entry("xs", &info.xs)?;
+                map.serialize_entry("ys", &info.ys)?;
+                map.serialize_entry("xoffs", &info.xoffs)?;
+                map.serialize_entry("yoffs", &info.yoffs)?;
+            },
+            Message::Unknown { ref payload, .. } => {
+                map.serialize_entry("type", "unknown")?;
+                map.serialize_entry("payload", &hex::encode(payload))?;
+            },
+        }
+
+        map.end()
+    }
+}
+
+impl MessageRecord {
+    /* A record of a message arriving now
+     */
+    pub fn new(message: Message) -> Self {
+        let time_ms = match SystemTime::now().duration_since(UNIX_EPOCH) {
+            Ok(t) => t.as_secs() * 1000 + t.subsec_nanos() as u64 / 1_000_000,
+            Err(_) => 0,
+        };
+
+        MessageRecord {
+            time_ms,
+            opcode: message.opcode(),
+            message,
+        }
+    }
+}
+
+impl ManualDirection {
+    fn from_u8(direction: u8) -> Option<ManualDirection> {
+        match direction {
+            10 => Some(ManualDirection::Forward),
+            11 => Some(ManualDirection::Backward),
+            12 => Some(ManualDirection::Left),
+            13 => Some(ManualDirection::Right),
+            _ => None,
+        }
+    }
+}
+
+impl Command {
+    /* Produces the complete wire frame for the command, header included
+     */
+    pub fn encode(&self) -> Vec<u8> {
+        let mut payload: Vec<u8> = Vec::new();
+
+        match *self {
+            Command::Dest { x, y, backwards } => {
+                payload.write_i32::<BigEndian>(x).unwrap();
+                payload.write_i32::<BigEndian>(y).unwrap();
+                payload.push(backwards as u8);
+            },
+            Command::Route { x, y } => {
+                payload.write_i32::<BigEndian>(x).unwrap();
+                payload.write_i32::<BigEndian>(y).unwrap();
+                payload.push(0);
+            },
+            Command::Charge => payload.push(0),
+            Command::Localize => payload.push(MODE_LOCALIZE),
+            Command::Free => payload.push(MODE_FREE),
+            Command::Stop => payload.push(MODE_STOP),
+            Command::Mode(mode) => payload.push(mode),
+            Command::Manual(direction) => payload.push(direction as u8),
+            Command::SpeedLimit(limit) => payload.push(limit),
+        }
+
+        frame(self.opcode(), payload)
+    }
+
+    /* Decodes a complete command frame, header included. Inverse of encode
+     */
+    pub fn decode(buf: &[u8]) -> Result<Command, ProtocolError> {
+        let (opcode, payload) = split_frame(buf)?;
+
+        Command::from_payload(opcode, payload)
+    }
+
+    /* Decodes the payload of a command frame with the given opcode
+     */
+    pub fn from_payload(opcode: u8, mut payload: &[u8]) -> Result<Command, ProtocolError> {
+        check_len(opcode, payload)?;
+
+        let command = match opcode {
+            OPCODE_DEST => {
+                Command::Dest {
+                    x: payload.read_i32::<BigEndian>().unwrap(),
+                    y: payload.read_i32::<BigEndian>().unwrap(),
+                    backwards: payload[0] != 0,
+                }
+            },
+            OPCODE_ROUTE => {
+                Command::Route {
+                    x: payload.read_i32::<BigEndian>().unwrap(),
+                    y: payload.read_i32::<BigEndian>().unwrap(),
+                }
+            },
+            OPCODE_CHARGE => Command::Charge,
+            OPCODE_MODE => {
+                match payload[0] {
+                    MODE_LOCALIZE => Command::Localize,
+                    MODE_FREE => Command::Free,
+                    MODE_STOP => Command::Stop,
+                    mode => Command::Mode(mode),
+                }
+            },
+            OPCODE_MANUAL => {
+                match ManualDirection::from_u8(payload[0]) {
+                    Some(direction) => Command::Manual(direction),
+                    None => { return Err( ProtocolError::new(ProtocolErrorKind::BadValue, opcode, payload) ) }
+                }
+            },
+            OPCODE_SPEED_LIMIT => {
+                Command::SpeedLimit(payload[0])
+            },
+            _ => { return Err( ProtocolError::new(ProtocolErrorKind::UnknownOpcode, opcode, payload) ) }
+        };
+
+        Ok(command)
+    }
+
+    /* The opcode this command is sent with
+     */
+    pub fn opcode(&self) -> u8 {
+        match *self {
+            Command::Dest { .. } => OPCODE_DEST,
+            Command::Route { .. } => OPCODE_ROUTE,
+            Command::Charge => OPCODE_CHARGE,
+            Command::Localize | Command::Free | Command::Stop | Command::Mode(_) => OPCODE_MODE,
+            Command::Manual(_) => OPCODE_MANUAL,
+            Command::SpeedLimit(_) => OPCODE_SPEED_LIMIT,
+        }
+    }
+}
+
+impl Frame {
+    /* The frame as sent over the wire, header included
+     */
+    pub fn encode(&self) -> Vec<u8> {
+        frame(self.opcode, self.payload.clone())
+    }
+}
+
+impl ProtocolStats {
+    /* Counts a frame which couldn't be decoded, or a run of garbage
+     */
+    pub fn add_error(&mut self, error: ProtocolError) {
+        self.errors += 1;
+
+        if error.kind == ProtocolErrorKind::Garbage {
+            self.skipped_bytes += error.length as u64;
+        }
+
+        self.last_error = Some(error);
+    }
+}
+
+impl<R: Read> FrameReader<R> {
+    pub fn new(reader: R) -> Self {
+        FrameReader {
+            reader,
+            pending: Vec::new(),
+            skipped: Vec::new(),
+        }
+    }
+
+    /* Blocks until a complete frame has been read from the stream. A header claiming a
+     * payload length its opcode is never sent with is taken as garbage: bytes are
+     * skipped until the header of a known opcode turns up, and returned as a Protocol
+     * error before the frame following them. Long runs of garbage are returned every
+     * MAX_GARBAGE_LEN bytes, see skipped
+     */
+    pub fn read_frame(&mut self) -> Result<Frame, RobotError> {
+        self.skipped.clear();
+
+        loop {
+            self.fill(FRAME_HEADER_LEN)?;
+
+            let opcode = self.pending[0];
+            let len = ((self.pending[1] as usize) << 8) | (self.pending[2] as usize);
+
+            // Unknown opcodes may be any length, so can't be told apart from garbage
+            let believable = match payload_len(opcode) {
+                Some((min, max)) => len >= min && len <= max,
+                None => self.skipped.is_empty(),
+            };
+
+            if believable && self.skipped.is_empty() {
+                self.fill(FRAME_HEADER_LEN + len)?;
+
+                let payload = self.pending[FRAME_HEADER_LEN..FRAME_HEADER_LEN + len].to_vec();
+                self.pending.drain(..FRAME_HEADER_LEN + len);
+
+                return Ok(Frame { opcode, payload });
+            }
+
+            if believable || self.skipped.len() >= MAX_GARBAGE_LEN {
+                return Err( RobotError::from(ProtocolError {
+                    kind: ProtocolErrorKind::Garbage,
+                    opcode: self.skipped[0],
+                    length: self.skipped.len(),
+                    bytes: self.skipped.iter().take(PROTOCOL_ERROR_BYTES).cloned().collect(),
+                }) );
+            }
+
+            // Skip to the next byte which could start the header of a known opcode, in one go
+            let next = match self.pending.iter().skip(1).position(|&b| payload_len(b).is_some()) {
+                Some(i) => i + 1,
+                None => self.pending.len(),
+            };
+
+            self.skipped.extend(self.pending.drain(..next));
+        }
+    }
+
+    /* All the bytes the last Garbage error was returned for, e.g. to record them, as
+     * the error only holds the first few
+     */
+    pub fn skipped(&self) -> &[u8] {
+        &self.skipped
+    }
+
+    /* Blocks until a complete frame has been read, and decodes it
+     */
+    pub fn read_message(&mut self) -> Result<Message, RobotError> {
+        let frame = self.read_frame()?;
+
+        Ok(Message::from_payload(frame.opcode, &frame.payload)?)
+    }
+
+    /* Reads from the stream until at least `len` bytes are pending
+     */
+    fn fill(&mut self, len: usize) -> Result<(), RobotError> {
+        if self.pending.len() < len {
+            let mut more = vec![0; len - self.pending.len()];
+
+            if let Err(e) = self.reader.read_exact(&mut more) {
+                return Err( RobotError::from(e) );
+            }
+
+            self.pending.extend(more);
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn decodes_position() {
+        let buf = [130, 0, 10, 0x40, 0x00, 0xff, 0xff, 0xff, 0x00, 0x00, 0x00, 0x06, 0x5e];
+
+        assert_eq!(decode(&buf).ok(), Some(Message::Position(RobotPose {
+            x: -256,
+            y: 1630,
+            angle: Angle::from_degrees(90.0),
+        })));
+    }
+
+    #[test]
+    fn decodes_battery_flags_and_voltage() {
+        let buf = [134, 0, 4, 2, 0x3a, 0x98, 87];
+
+        assert_eq!(decode(&buf).ok(), Some(Message::Battery(BatteryStatus {
+            charging: false,
+            finished: true,
+            voltage: 15000,
+            percentage: 87,
+        })));
+    }
+
+    #[test]
+    fn decodes_unknown_states_without_panicking() {
+        assert_eq!(decode(&[139, 0, 1, 255]).ok(), Some(Message::State(RobotState::Undef)));
+        assert_eq!(decode(&[139, 0, 1, 42]).ok(), Some(Message::State(RobotState::Unknown(42))));
+    }
+
+    #[test]
+    fn keeps_unknown_opcodes() {
+        let buf = [200, 0, 2, 1, 2];
+
+        assert_eq!(decode(&buf).ok(), Some(Message::Unknown { opcode: 200, payload: vec![1, 2] }));
+    }
+
+    #[test]
+    fn rejects_short_frames() {
+        assert!(decode(&[130, 0, 10, 0, 0]).is_err());
+        assert!(decode(&[130, 0, 2, 0, 0]).is_err());
+        assert!(decode(&[139]).is_err());
+    }
+
+    #[test]
+    fn describes_bad_frames() {
+        let error = decode(&[130, 0, 2, 0, 0]).err().unwrap();
+
+        assert_eq!(error, ProtocolError { kind: ProtocolErrorKind::BadLength, opcode: 130, length: 2, bytes: vec![130, 0, 2, 0, 0] });
+        assert_eq!(error.to_string(), "Frame with opcode 130 has a 2 byte payload, expected 10: 8200020000");
+
+        let error = decode(&[139, 0, 3, 1]).err().unwrap();
+        assert_eq!((error.kind, error.length, error.bytes), (ProtocolErrorKind::Truncated, 3, vec![139, 0, 1, 1]));
+
+        assert_eq!(decode(&[139, 0, 2, 1, 1]).err().map(|e| e.kind), Some(ProtocolErrorKind::BadLength));
+        assert_eq!(Command::decode(&[59, 0, 1, 9]).err().map(|e| e.kind), Some(ProtocolErrorKind::BadValue));
+        assert_eq!(Command::decode(&[99, 0, 0]).err().map(|e| e.kind), Some(ProtocolErrorKind::UnknownOpcode));
+
+        // Long payloads are cut short in errors
+        let error = ProtocolError::new(ProtocolErrorKind::BadValue, 138, &[0; 100]);
+        assert_eq!((error.length, error.bytes.len()), (100, PROTOCOL_ERROR_BYTES));
+    }
+
+    #[test]
+    fn resynchronizes_after_garbage() {
+        // A position claiming 12 bytes, cut short by a state message
+        let stream: &[u8] = &[130, 0, 12, 0xaa, 0xbb, 139, 0, 1, 2, 200, 0, 1, 7, 139, 0, 1, 3];
+        let mut reader = FrameReader::new(stream);
+        let mut stats = ProtocolStats::default();
+
+        match reader.read_frame() {
+            Err(RobotError { protocol: Some(error), .. }) => {
+                assert_eq!(error.kind, ProtocolErrorKind::Garbage);
+                assert_eq!(error.bytes, vec![130, 0, 12, 0xaa, 0xbb]);
+                stats.add_error(*error);
+            },
+            _ => panic!("garbage not reported"),
+        }
+
+        assert_eq!(reader.skipped(), &[130, 0, 12, 0xaa, 0xbb]);
+
+        assert_eq!(reader.read_message().ok(), Some(Message::State(RobotState::Fwd)));
+        assert_eq!(reader.read_message().ok(), Some(Message::Unknown { opcode: 200, payload: vec![7] }));
+        assert_eq!(reader.read_message().ok(), Some(Message::State(RobotState::Rev)));
+        assert!(reader.read_frame().is_err());
+
+        assert_eq!((stats.errors, stats.skipped_bytes), (1, 5));
+    }
+
+    #[test]
+    fn reports_long_garbage_in_parts() {
+        // Positions claiming 65535 bytes
+        let mut stream = [130, 0xff, 0xff].repeat(50_000);
+        stream.extend(&[139, 0, 1, 2]);
+        let mut reader = FrameReader::new(&stream[..]);
+        let mut skipped = 0;
+
+        for _ in 0..3 {
+            match reader.read_frame() {
+                Err(RobotError { protocol: Some(error), .. }) => {
+                    assert_eq!((error.kind, error.length), (ProtocolErrorKind::Garbage, reader.skipped().len()));
+                    assert!(error.bytes.len() <= PROTOCOL_ERROR_BYTES);
+                    skipped += error.length;
+                },
+                _ => panic!("garbage not reported"),
+            }
+        }
+
+        assert_eq!(skipped, 150_000);
+        assert_eq!(reader.read_message().ok(), Some(Message::State(RobotState::Fwd)));
+    }
+
+    #[test]
+    fn reads_consecutive_frames() {
+        let stream: &[u8] = &[139, 0, 1, 2, 140, 0, 8, 0, 1, 0, 2, 0, 3, 0, 4];
+        let mut reader = FrameReader::new(stream);
+
+        assert_eq!(reader.read_message().ok(), Some(Message::State(RobotState::Fwd)));
+        assert_eq!(reader.read_message().ok(), Some(Message::RobotInfo(RobotInfo { xs: 1, ys: 2, xoffs: 3, yoffs: 4 })));
+        assert!(reader.read_frame().is_err());
+    }
+
+    #[test]
+    fn decodes_captured_robot_info() {
+        // As sent by a robot right after connecting, and by the simulator
+        let frame = [0x8c, 0x00, 0x08, 0x01, 0xe0, 0x01, 0x90, 0xff, 0x88, 0x00, 0x00];
+        let info = RobotInfo { xs: 480, ys: 400, xoffs: -120, yoffs: 0 };
+
+        assert_eq!(decode(&frame).ok(), Some(Message::RobotInfo(info)));
+        assert_eq!(Message::RobotInfo(info).encode(), frame.to_vec());
+        assert!(decode(&[0x8c, 0x00, 0x06, 0x01, 0xe0, 0x01, 0x90, 0xff, 0x88]).is_err());
+    }
+
+    #[test]
+    fn messages_round_trip() {
+        let messages = vec![
+            Message::Position(RobotPose { x: -1, y: 2, angle: Angle::from_degrees(-45.0) }),
+            Message::Battery(BatteryStatus { charging: true, finished: true, voltage: 16500, percentage: 100 }),
+            Message::TofHeightMap(HeightMap {
+                xsamps: 2,
+                ysamps: 1,
+                angle: Angle::from_degrees(-90.0),
+                xorig: -5,
+                yorig: 70000,
+                unit_size: 40,
+                cells: vec![HeightMapCell::Floor, HeightMapCell::Unknown(200)],
+            }),
+            Message::State(RobotState::Undef),
+            Message::State(RobotState::Unknown(9)),
+            Message::RobotInfo(RobotInfo { xs: 480, ys: 400, xoffs: -120, yoffs: 0 }),
+            Message::Unknown { opcode: 131, payload: vec![4, 5] },
+        ];
+
+        for message in messages {
+            assert_eq!(decode(&message.encode()).ok(), Some(message));
+        }
+    }
+
+    #[test]
+    fn encodes_route_like_the_firmware_expects() {
+        let buf = Command::Route { x: -256, y: 1630 }.encode();
+
+        assert_eq!(buf, vec![56, 0, 9, 0xff, 0xff, 0xff, 0x00, 0x00, 0x00, 0x06, 0x5e, 0]);
+    }
+
+    #[test]
+    fn encodes_modes() {
+        assert_eq!(Command::Localize.encode(), vec![58, 0, 1, 3]);
+        assert_eq!(Command::Free.encode(), vec![58, 0, 1, 5]);
+        assert_eq!(Command::Stop.encode(), vec![58, 0, 1, 8]);
+    }
+
+    #[test]
+    fn commands_round_trip() {
+        let commands = vec![
+            Command::Dest { x: 1, y: -2, backwards: true },
+            Command::Route { x: i32::MAX, y: i32::MIN },
+            Command::Charge,
+            Command::Localize,
+            Command::Free,
+            Command::Stop,
+            Command::Mode(7),
+            Command::Manual(ManualDirection::Left),
+            Command::SpeedLimit(20),
+        ];
+
+        for command in commands {
+            assert_eq!(Command::decode(&command.encode()).ok(), Some(command));
+        }
+    }
+
+    #[test]
+    fn writes_messages_as_json() {
+        let pose = RobotPose { x: 10, y: -20, angle: Angle::from_degrees(-90.0) };
+        let record = MessageRecord { time_ms: 1234, opcode: OPCODE_POSITION, message: Message::Position(pose) };
+
+        assert_eq!(serde_json::to_string(&record).unwrap(),
+                   r#"{"time_ms":1234,"opcode":130,"type":"position","x":10,"y":-20,"angle":-90.0}"#);
+
+        let record = MessageRecord::new(Message::State(RobotState::Unknown(42)));
+
+        assert_eq!(record.opcode, OPCODE_STATE);
+        assert!(serde_json::to_string(&record).unwrap().ends_with(r#""opcode":139,"type":"state","state":"Unknown(42)","code":42}"#));
+        assert_eq!(serde_json::to_string(&Message::Unknown { opcode: 200, payload: vec![1, 171] }).unwrap(),
+                   r#"{"type":"unknown","payload":"01ab"}"#);
+    }
+}
@@ -1,236 +1,520 @@
 use super::*;
-use std::net::{TcpStream, Shutdown};
-use std::time::Duration;
-use std::io::{Read, Write};
-use byteorder::{BigEndian, ReadBytesExt};
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::thread;
+
+/* How long to wait for the robot to report its position */
+const TIMEOUT_SECS: u64 = 5;
+
+/* How close turn_to gets to the requested heading */
+const HEADING_TOLERANCE_DEG: f64 = 5.0;
+
+/* How close goto_with_heading must get before turning, and scripts to have arrived */
+pub(super) const ARRIVAL_TOLERANCE_MM: i32 = 200;
+
+/* How long goto_waypoint may block while driving to a waypoint with a heading */
+const WAYPOINT_TIMEOUT_SECS: u64 = 300;
+
+/* Time allowed for a single manual turning step */
+const TURN_STEP_MS: u64 = 300;
+
+/* How long the robot may take to act on a command, e.g. to leave Idle after a goto */
+pub(super) const COMMAND_LATENCY_MS: u64 = 2000;
 
 impl PuluRobot for Robot {
-    /* Establish connection with robot.
+    /* (Re-)establish connection with robot, and start reading from it.
      * Note: Can only be used after initializing a Robot object. See from_config
      */
     fn connect(&mut self) -> Result<(), RobotError> {
-        let robo_addr = (self.config.robot_address.to_owned() + ":" + &self.config.robot_port).parse().unwrap();
-        self.stream = match TcpStream::connect_timeout(&robo_addr, Duration::new(5,0)) {
-            Ok(s) => s,
-            Err(_) => { return Err( RobotError::new(RobotErrorType::Connection) ) } 
-        };
-        return Ok(())
+        link::stop(&self.link);
+        link::start(self)
     }
 
     /* Initilize a Robot object from a config file.
-     * Currently also attempts to connect to the robot.
+     * Also connects to the robot, which stays connected until disconnect is called.
      */
     fn from_config(config_path: &str) -> Result<Robot, RobotError> {
 
-        let mut config = Config::new();
+        let config = Config::from_file(config_path)?;
+
+        Robot::with_config(config, config_path)
+    }
 
-        config = match config.from_file(config_path) {
-            Ok(s) => s,
-            Err(_) => { panic!("Problems reading from config file"); }
+    /* Initilize a Robot object from an already loaded config, which is written back to
+     * config_path when waypoints are saved. Also connects to the robot.
+     */
+    fn with_config(config: Config, config_path: &str) -> Result<Robot, RobotError> {
+        let robot = Robot {
+            config_path: String::from(config_path),
+            config,
+            telemetry: Arc::new(Mutex::new(Telemetry::default())),
+            link: Arc::new(Mutex::new(Link::new())),
+            subscribers: Arc::new(Mutex::new(Vec::new())),
+            link_subscribers: Arc::new(Mutex::new(Vec::new())),
+            battery_alarms: Arc::new(Mutex::new(Vec::new())),
         };
 
-        let robo_addr = (config.robot_address.to_owned() + ":" + &config.robot_port).parse().unwrap();
-        match TcpStream::connect_timeout(&robo_addr, Duration::new(5,0)) {
-            Ok(s) => {
-                let robot = Robot {
-                    stream: s,
-                    config_path: String::from(config_path),
-                    config: config,
-                };
-                return Ok(robot);
-            },
-            Err(_) => Err( RobotError::new(RobotErrorType::Connection) )
-        }
+        link::start(&robot)?;
+
+        Ok(robot)
     }
 
+    /* Closes the connection for good, without reconnecting
+     */
     fn disconnect(&mut self) {
-        self.stream.shutdown(Shutdown::Both).unwrap();
+        link::stop(&self.link);
     }
 
-    /* Will connect to the robot, and listen for information from the robot.
-     * Disconnects and returns when a location-packet has been received.
+    /* Returns the last location reported by the robot. If none has been received yet,
+     * waits for the first one to arrive.
      */
     fn get_location(&mut self) -> Result<RobotLocation, RobotError> {
-        let x:i32;
-        let y:i32;
-
-        match self.connect() {
-            Ok(_) => {},
-            Err(_) => { return Err( RobotError::new(RobotErrorType::Connection) ) }
-        };
-
-        loop {
-            let mut cmd_buf = [0; 3];
-            self.stream.read_exact(&mut cmd_buf).unwrap();
+        Ok(self.get_pose()?.location())
+    }
 
-            let len: i32 = ((cmd_buf[1] as i32) << 8) | (cmd_buf[2] as i32);
+    /* Returns the last position and heading reported by the robot. If none has been
+     * received yet, waits for the first one to arrive.
+     */
+    fn get_pose(&mut self) -> Result<RobotPose, RobotError> {
+        self.cached_or_wait(
+            |telemetry| telemetry.pose,
+            |message| match *message {
+                Message::Position(pose) => Some(pose),
+                _ => None,
+            },
+            OPCODE_POSITION,
+            Duration::new(TIMEOUT_SECS, 0))
+    }
 
-            let mut buf: Vec<u8> = vec![0;len as usize];
-            self.stream.read_exact(&mut buf[..]).unwrap();
+    /* Returns the last state reported by the robot. If none has been received yet,
+     * waits for the first one to arrive.
+     */
+    fn get_state(&mut self) -> Result<RobotState, RobotError> {
+        self.cached_or_wait(
+            |telemetry| telemetry.state,
+            |message| match *message {
+                Message::State(state) => Some(state),
+                _ => None,
+            },
+            OPCODE_STATE,
+            Duration::new(TIMEOUT_SECS, 0))
+    }
 
-            if cmd_buf[0] == 130 {
+    /* Returns the last battery status reported by the robot. If none has been received
+     * yet, waits for the first one to arrive.
+     */
+    fn get_battery(&mut self) -> Result<BatteryStatus, RobotError> {
+        self.cached_or_wait(
+            |telemetry| telemetry.battery,
+            |message| match *message {
+                Message::Battery(battery) => Some(battery),
+                _ => None,
+            },
+            OPCODE_BATTERY,
+            Duration::new(TIMEOUT_SECS, 0))
+    }
 
-                let mut buf_x = &buf[2..7];
-                let mut buf_y = &buf[6..11];
+    /* Returns the last height map sent by the robot. If none has been received yet,
+     * waits for the first one to arrive.
+     */
+    fn get_height_map(&mut self) -> Result<HeightMap, RobotError> {
+        self.cached_or_wait(
+            |telemetry| telemetry.height_map.clone(),
+            |message| match *message {
+                Message::TofHeightMap(ref map) => Some(map.clone()),
+                _ => None,
+            },
+            OPCODE_TOF_HMAP,
+            Duration::new(TIMEOUT_SECS, 0))
+    }
 
-                x = buf_x.read_i32::<BigEndian>().unwrap();
-                y = buf_y.read_i32::<BigEndian>().unwrap();
+    /* Returns the robot's footprint. The robot sends it once when connected to, so this
+     * only waits if it hasn't arrived yet
+     */
+    fn get_robot_info(&mut self) -> Result<RobotInfo, RobotError> {
+        self.cached_or_wait(
+            |telemetry| telemetry.robot_info,
+            |message| match *message {
+                Message::RobotInfo(info) => Some(info),
+                _ => None,
+            },
+            OPCODE_ROBOT_INFO,
+            Duration::new(TIMEOUT_SECS, 0))
+    }
 
-                break;
-            }
-        }
+    /* Returns counts of the frames received from the robot, and of those which couldn't
+     * be decoded
+     */
+    fn get_protocol_stats(&self) -> ProtocolStats {
+        self.telemetry.lock().unwrap().protocol.clone()
+    }
 
-        self.disconnect();
+    /* How far the robot can drive `distance` mm straight ahead, or back when negative,
+     * before its body reaches an obstacle seen in the last height map. None if the way
+     * looks clear
+     */
+    fn obstacle_ahead(&mut self, distance: i32) -> Result<Option<f64>, RobotError> {
+        let info = self.get_robot_info()?;
+        let map = self.get_height_map()?;
 
-        return Ok(RobotLocation { x: x, y: y });
+        Ok(map.obstacle_in_path(&info, distance as f64))
     }
 
-    // TODO 
-    fn get_state() -> Result<RobotState, RobotError> {
-        return Err( RobotError::new(RobotErrorType::NotYetImplemented) )
+    /* Registers a callback for when the battery percentage drops below `threshold`, e.g.
+     * to send the robot to charge. It is called once per discharge, from the reader thread.
+     */
+    fn on_low_battery(&mut self, threshold: u8, callback: BatteryCallback) {
+        self.battery_alarms.lock().unwrap().push(BatteryAlarm {
+            threshold,
+            callback,
+            triggered: false,
+        });
     }
 
-    /* Will connect to the robot, and send a request to unblock the wheels
+    /* Blocks until the robot reports the given state, e.g. Idle after a goto.
+     * Returns immediately if the robot is already in that state, unless a command was
+     * sent less than COMMAND_LATENCY_MS ago: the robot may still leave the state because
+     * of it, so it is given until then to do so, and then waited for to come back.
      */
-    fn free(&mut self) -> Result<(), RobotError> {
-        let mut buf = [0; 4];
+    fn wait_for_state(&mut self, state: RobotState, timeout: Duration) -> Result<(), RobotError> {
+        let deadline = Instant::now() + timeout;
+        // Subscribe first, not to miss the robot leaving the state
+        let rx = self.subscribe();
+
+        let latency = Duration::from_millis(COMMAND_LATENCY_MS);
+        let settle = match self.link.lock().unwrap().sent_at {
+            Some(sent_at) if sent_at.elapsed() < latency => latency - sent_at.elapsed(),
+            _ => Duration::new(0, 0),
+        };
 
-        buf[0] = 58;
-        buf[1] = 0;
-        buf[2] = 1;
-        buf[3] = 5;
+        if self.telemetry.lock().unwrap().state == Some(state) {
+            let left = wait_for(&rx, settle.min(timeout),
+                                |message| match *message { Message::State(s) if s != state => Some(()), _ => None });
 
-        match self.connect() {
-            Ok(_) => {},
-            Err(_) => { return Err( RobotError::new(RobotErrorType::Connection) ) }
-        };
-        
-        match self.stream.write_all(&mut buf) {
-            Ok(_) => {},
-            Err(_) => { return Err( RobotError::new(RobotErrorType::Write) ) }
+            match left {
+                Ok(_) => {},
+                // Stayed in the state
+                Err(ref e) if e.err_type == RobotErrorType::Timeout => { return Ok(()) },
+                Err(e) => { return Err( e.with_address(self.address()).with_opcode(OPCODE_STATE) ) }
+            }
         }
 
-        self.disconnect();
+        wait_for(&rx, remaining(deadline), |message| if *message == Message::State(state) { Some(()) } else { None })
+            .map_err(|e| e.with_address(self.address()).with_opcode(OPCODE_STATE))
+    }
 
-        Ok(())
+    /* Sends a request to unblock the wheels
+     */
+    fn free(&mut self) -> Result<(), RobotError> {
+        self.send(&Command::Free)
     }
 
-    /* Routes the robot to a point defined in the config file
+    /* Routes the robot to a waypoint defined in the config file. Returns once the route
+     * is sent, unless the waypoint was saved with a heading: then blocks until the robot
+     * has arrived and turned to face it, and fails with NotArrived if it didn't get there
      */
-    fn goto_point(&mut self, point: &str) -> Result<(), RobotError> {
-        // Get point from config file   
-        match self.config.get_point(point) {
-            // Order robot to go the that location
-            Ok(p) => match self.goto(p.0, p.1) {
-                Ok(_) => Ok(()),
-                Err(_) => Err( RobotError::new(RobotErrorType::Write) )
-            },
-            Err(_) => Err( RobotError::new(RobotErrorType::Read) )
+    fn goto_waypoint(&mut self, name: &str) -> Result<(), RobotError> {
+        let waypoint = match self.config.get_waypoint(name) {
+            Ok(w) => w,
+            Err(e) => { return Err( RobotError::from(e) ) }
+        };
+
+        let heading = match waypoint.heading {
+            Some(h) => h,
+            None => { return self.goto(waypoint.x, waypoint.y) }
+        };
+
+        match self.goto_with_heading(waypoint.x, waypoint.y, Angle::from_degrees(heading),
+                                     Duration::new(WAYPOINT_TIMEOUT_SECS, 0))? {
+            GotoOutcome::Arrived => Ok(()),
+            outcome => Err( RobotError::new(RobotErrorType::NotArrived)
+                .with_source(format!("{} on the way to waypoint {}", outcome, name)) ),
         }
     }
 
     /* Routes the robot to specific coordinates
      */
     fn goto(&mut self, x: i32, y: i32) -> Result<(), RobotError> {
+        self.send(&Command::Route { x, y })
+    }
 
-        match self.connect() {
-            Ok(_) => {},
-            Err(_) => { return Err( RobotError::new(RobotErrorType::Connection) ) }
-        };
- 
-        let mut buf = [0; 12];
+    /* Drives the robot `distance` mm straight ahead from where it is, or backwards if
+     * negative, without route planning
+     */
+    fn forward(&mut self, distance: i32) -> Result<(), RobotError> {
+        let pose = self.get_pose()?;
+        let angle = pose.angle.radians();
+
+        self.send(&Command::Dest {
+            x: pose.x + (distance as f64 * angle.cos()).round() as i32,
+            y: pose.y + (distance as f64 * angle.sin()).round() as i32,
+            backwards: distance < 0,
+        })
+    }
+
+    /* Routes the robot to the point `dx`, `dy` mm away from where it is, along the axes
+     * of the map rather than the robot's heading
+     */
+    fn move_by(&mut self, dx: i32, dy: i32) -> Result<(), RobotError> {
+        let pose = self.get_pose()?;
+
+        self.goto(pose.x + dx, pose.y + dy)
+    }
 
-        buf[0] = 56;
+    /* Turns the robot on the spot by `degrees`, positive being counter-clockwise. Blocks
+     * for at most `timeout`, see turn_to
+     */
+    fn turn(&mut self, degrees: f64, timeout: Duration) -> Result<(), RobotError> {
+        let heading = Angle::from_degrees(self.get_pose()?.angle.degrees() + degrees);
 
-        buf[1] = 0;
-        buf[2] = 9;
+        self.turn_to(heading, timeout)
+    }
 
-        buf[3] = (x>>24) as u8;
-        buf[4] = (x>>16) as u8;
-        buf[5] = (x>>8) as u8;
-        buf[6] = (x>>0) as u8;
+    /* Routes the robot to specific coordinates, and blocks until it is idle within
+     * `tolerance` mm of them, has stopped elsewhere or got blocked, or `timeout` runs out.
+     * The robot is left as it is if it didn't arrive, call stop to cancel the route.
+     */
+    fn goto_and_wait(&mut self, x: i32, y: i32, tolerance: i32, timeout: Duration) -> Result<GotoOutcome, RobotError> {
+        // Subscribe first, not to miss the robot setting off
+        let rx = self.subscribe();
+        let telemetry = self.telemetry.lock().unwrap().clone();
 
-        buf[7] = (y>>24) as u8;
-        buf[8] = (y>>16) as u8;
-        buf[9] = (y>>8) as u8;
-        buf[10] = (y>>0) as u8;
+        self.goto(x, y)?;
 
-        buf[11] = 0;
+        arrival::wait_for_arrival(&rx, &telemetry, x, y, tolerance, timeout).map_err(|e| e.with_address(self.address()))
+    }
 
-        match self.stream.write_all(&mut buf) {
-            Ok(_) => {},
-            Err(_) => { return Err( RobotError::new(RobotErrorType::Write) ) }
+    /* Routes the robot to specific coordinates, waits for it to get there, and then turns
+     * it to face `heading`. Blocks for at most `timeout`.
+     */
+    fn goto_with_heading(&mut self, x: i32, y: i32, heading: Angle, timeout: Duration) -> Result<GotoOutcome, RobotError> {
+        let deadline = Instant::now() + timeout;
+        let outcome = self.goto_and_wait(x, y, ARRIVAL_TOLERANCE_MM, timeout)?;
+
+        if outcome == GotoOutcome::Arrived {
+            self.turn_to(heading, remaining(deadline))?;
         }
 
-        self.disconnect();
+        Ok(outcome)
+    }
 
-        Ok(())
-    } 
+    /* Turns the robot on the spot, one manual step at a time, until it faces `heading`
+     */
+    fn turn_to(&mut self, heading: Angle, timeout: Duration) -> Result<(), RobotError> {
+        let deadline = Instant::now() + timeout;
+
+        loop {
+            let error = heading.difference(self.get_pose()?.angle);
+
+            if error.degrees().abs() <= HEADING_TOLERANCE_DEG {
+                return Ok(());
+            }
+
+            if Instant::now() >= deadline {
+                return Err( RobotError::new(RobotErrorType::Timeout)
+                    .with_address(self.address())
+                    .with_source(format!("still {:.0} degrees off the heading", error.degrees().abs())) );
+            }
+
+            if error.raw() > 0 {
+                self.send(&Command::Manual(ManualDirection::Left))?;
+            } else {
+                self.send(&Command::Manual(ManualDirection::Right))?;
+            }
+
+            // Give the step time to finish before measuring again
+            thread::sleep(Duration::from_millis(TURN_STEP_MS));
+        }
+    }
 
     /* Asks the robot to localize itself, i.e. resetting its internal coordinate system
      */
     fn localize(&mut self) -> Result<(), RobotError> {
-        let mut buf = [0; 4];
+        self.send(&Command::Localize)
+    }
 
-        buf[0] = 58;
-        buf[1] = 0;
-        buf[2] = 1;
-        buf[3] = 3;
+    /* Asks the robot to stop whatever it is currently doing
+     */
+    fn stop(&mut self) -> Result<(), RobotError> {
+        self.send(&Command::Stop)
+    }
 
-        match self.connect() {
-            Ok(_) => {},
-            Err(_) => { return Err( RobotError::new(RobotErrorType::Connection) ) }
+    /* Saves the current position of the robot as a waypoint in the config file. With
+     * `with_heading`, also its heading, which goto_waypoint then waits to turn to
+     */
+    fn save_waypoint(&mut self, name: &str, tags: Vec<String>, with_heading: bool) -> Result<(), RobotError> {
+        let pose = self.get_pose()?;
+        let waypoint = Waypoint {
+            x: pose.x,
+            y: pose.y,
+            heading: if with_heading { Some(pose.angle.degrees()) } else { None },
+            tags,
         };
- 
-    
-        match self.stream.write_all(&mut buf) {
-            Ok(_) => {},
-            Err(_) => { return Err( RobotError::new(RobotErrorType::Write) ) }
-        }
 
-        self.disconnect();
+        self.config.set_waypoint(name, waypoint)?;
+        self.config.write(&self.config_path)?;
 
         Ok(())
     }
 
-    /* Asks the robot to stop whatever it is currently doing
+    /* Sends a single command over the open connection, or queues it while reconnecting
+     * if the config allows it
      */
-    fn stop(&mut self) -> Result<(), RobotError> {
-        let mut buf = [0; 4];
+    fn send(&mut self, command: &Command) -> Result<(), RobotError> {
+        self.link.lock().unwrap().send(command, &self.config.reconnect).map_err(|e| e.with_address(self.address()))
+    }
 
-        buf[0] = 58;
-        buf[1] = 0;
-        buf[2] = 1;
-        buf[3] = 8;
+    /* Returns a channel receiving every message decoded from now on, also across
+     * reconnects. Dropping the receiver unsubscribes.
+     */
+    fn subscribe(&mut self) -> Receiver<Message> {
+        let (tx, rx) = mpsc::channel();
+        self.subscribers.lock().unwrap().push(tx);
+        rx
+    }
 
-        match self.connect() {
-            Ok(_) => {},
-            Err(_) => { return Err( RobotError::new(RobotErrorType::Connection) ) }
-        };
-        
-        match self.stream.write_all(&mut buf) {
-            Ok(_) => {},
-            Err(_) => { return Err( RobotError::new(RobotErrorType::Write) ) }
-        }
-        
-        self.disconnect();
+    /* Returns a channel receiving every change of the connection state from now on
+     */
+    fn subscribe_link(&mut self) -> Receiver<LinkState> {
+        let (tx, rx) = mpsc::channel();
+        self.link_subscribers.lock().unwrap().push(tx);
+        rx
+    }
 
+    /* Records every frame sent to and received from the robot into a capture file,
+     * replacing any capture already running. See capture::CaptureWriter
+     */
+    fn start_capture(&mut self, capture_path: &str) -> Result<(), RobotError> {
+        let capture = CaptureWriter::create(capture_path)?;
+        self.link.lock().unwrap().capture = Some(capture);
         Ok(())
     }
 
-    /* Saves the current location of the robot as a point to the config file
+    fn stop_capture(&mut self) {
+        self.link.lock().unwrap().capture = None;
+    }
+
+    /* Visits the waypoints of a mission in order, blocking until it has completed or a
+     * step has failed. See mission::run
+     */
+    fn run_mission<F: FnMut(&MissionEvent)>(&mut self, mission: &Mission, on_event: F) -> Result<GotoOutcome, RobotError> {
+        mission::run(self, mission, on_event)
+    }
+
+    /* Runs a script, handing the commands it doesn't know itself to `command`, and
+     * reporting every step through `on_step`. Stops at the first step that fails.
+     */
+    fn run_script<C, F>(&mut self, script: &Script, command: C, on_step: F) -> Result<(), ScriptError>
+        where C: FnMut(&mut Robot, &[String]) -> Result<(), String>, F: FnMut(&ScriptStep) {
+        script::run(self, script, command, on_step)
+    }
+}
+
+impl Robot {
+    /* The robot's HOST:PORT, for error messages
      */
-    fn save_location(&mut self, location: &str) -> Result<(), RobotError> { 
-        let robo_location = self.get_location()?;
+    pub(super) fn address(&self) -> String {
+        format!("{}:{}", self.config.robot_address, self.config.robot_port)
+    }
+
+    /* Returns the value picked from the cached telemetry if there is one, otherwise waits
+     * up to `timeout` for a message with `opcode` to pick it from
+     */
+    fn cached_or_wait<T, C, S>(&mut self, cached: C, select: S, opcode: u8, timeout: Duration) -> Result<T, RobotError>
+        where C: Fn(&Telemetry) -> Option<T>, S: Fn(&Message) -> Option<T> {
+
+        if let Some(value) = cached(&self.telemetry.lock().unwrap()) {
+            return Ok(value);
+        }
+
+        let rx = self.subscribe();
+
+        // The value may have arrived in between
+        if let Some(value) = cached(&self.telemetry.lock().unwrap()) {
+            return Ok(value);
+        }
+
+        wait_for(&rx, timeout, select).map_err(|e| e.with_address(self.address()).with_opcode(opcode))
+    }
+}
+
+/* Time left until `deadline`, zero if it has passed
+ */
+fn remaining(deadline: Instant) -> Duration {
+    let now = Instant::now();
+
+    if now >= deadline { Duration::new(0, 0) } else { deadline - now }
+}
+
+/* Waits up to `timeout` for a message for which `select` returns a value. The caller
+ * adds the address and opcode to the error.
+ */
+fn wait_for<T, S>(rx: &Receiver<Message>, timeout: Duration, select: S) -> Result<T, RobotError>
+    where S: Fn(&Message) -> Option<T> {
+
+    let deadline = Instant::now() + timeout;
 
-        match self.config.set_point(location, robo_location.x, robo_location.y) {
-            Ok(_) => {
-                match self.config.write(&self.config_path) {
-                    Ok(_) => Ok(()),
-                    Err(_) => Err( RobotError::new(RobotErrorType::Write) )
+    loop {
+        let now = Instant::now();
+
+        if now >= deadline {
+            break;
+        }
+
+        match rx.recv_timeout(deadline - now) {
+            Ok(message) => {
+                if let Some(value) = select(&message) {
+                    return Ok(value);
                 }
             },
-            Err(_) => Err( RobotError::new(RobotErrorType::Write) )
+            Err(RecvTimeoutError::Timeout) => break,
+            Err(RecvTimeoutError::Disconnected) => {
+                return Err( RobotError::new(RobotErrorType::Read).with_source("the robot was disconnected") )
+            }
         }
     }
+
+    Err( RobotError::new(RobotErrorType::Timeout)
+        .with_source(format!("no reply within {} ms", timeout.as_millis())) )
+}
+
+impl Drop for Robot {
+    fn drop(&mut self) {
+        self.disconnect();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{env, fs, process};
+
+    #[test]
+    fn saves_the_heading_only_on_request() {
+        let settings = SimulatorSettings { speed: 5000.0, turn_speed: 3600.0, interval_ms: 10, ..SimulatorSettings::default() };
+        let simulator = Simulator::start("127.0.0.1:0", settings).ok().unwrap();
+        let config_path = env::temp_dir().join(format!("pulurobot-test-waypoints-{}.json", process::id()));
+        fs::File::create(&config_path).unwrap();
+
+        let mut config = Config::new();
+        config.robot_address = simulator.local_addr().ip().to_string();
+        config.robot_port = simulator.local_addr().port().to_string();
+
+        let mut robot = Robot::with_config(config, config_path.to_str().unwrap()).ok().unwrap();
+        assert!(robot.save_waypoint("origin", Vec::new(), false).is_ok());
+        assert!(robot.save_waypoint("facing", Vec::new(), true).is_ok());
+        let _ = fs::remove_file(&config_path);
+
+        assert_eq!(robot.config.get_waypoint("origin").ok().and_then(|w| w.heading), None);
+        assert_eq!(robot.config.get_waypoint("facing").ok().and_then(|w| w.heading), Some(0.0));
+
+        // Without a heading, goto_waypoint only sends the route
+        assert_eq!(robot.goto_and_wait(0, 3000, 50, Duration::new(10, 0)).ok(), Some(GotoOutcome::Arrived));
+        assert!(robot.goto_waypoint("origin").is_ok());
+        assert_ne!(simulator.pose().location(), RobotLocation { x: 0, y: 0 });
+
+        // With one, it waits to arrive and turn
+        assert!(robot.goto_waypoint("facing").is_ok());
+        assert_eq!(simulator.pose().location(), RobotLocation { x: 0, y: 0 });
+        assert!(simulator.pose().angle.degrees().abs() <= 5.0);
+    }
 }
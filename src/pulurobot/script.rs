@@ -0,0 +1,495 @@
+
+/* Scripts of console commands, with waits, loops and conditions, e.g.
+
+       localize
+       repeat 3
+           goto kitchen
+           wait-arrival 120
+           if battery < 20
+               goto dock
+               wait-arrival
+               wait-state charging
+           end
+       end
+ */
+
+use super::*;
+use super::pulurobot::ARRIVAL_TOLERANCE_MM;
+use std::fs::File;
+use std::io::Read;
+use std::thread;
+
+/* How long wait-arrival and wait-state wait, unless the script says otherwise */
+const WAIT_ARRIVAL_SECS: u64 = 300;
+const WAIT_STATE_SECS: u64 = 60;
+
+/* How a block of lines ended */
+#[derive(PartialEq)]
+enum Closing {
+    End,
+    Else,
+    Eof,
+}
+
+/* The target of the last goto, to wait for */
+struct Arrival {
+    rx: Receiver<Message>,
+    telemetry: Telemetry,
+    x: i32,
+    y: i32,
+    heading: Option<f64>,
+}
+
+struct Runner<'a, C, F> {
+    robot: &'a mut Robot,
+    command: C,
+    on_step: F,
+    arrival: Option<Arrival>,
+}
+
+fn error(line: usize, message: &str) -> ScriptError {
+    ScriptError { line, message: message.to_owned() }
+}
+
+fn parse_secs(line: usize, secs: &str) -> Result<Duration, ScriptError> {
+    match secs.parse::<f64>() {
+        Ok(s) if s >= 0.0 && s.is_finite() => Ok(Duration::from_millis((s * 1000.0) as u64)),
+        _ => Err(error(line, "expected a number of seconds")),
+    }
+}
+
+/* State names as printed by the console, in any case, e.g. idle or Charging
+ */
+fn parse_state(line: usize, name: &str) -> Result<RobotState, ScriptError> {
+    let states = [RobotState::Undef, RobotState::Idle, RobotState::Think, RobotState::Fwd, RobotState::Rev,
+                  RobotState::Left, RobotState::Right, RobotState::Charging, RobotState::Daijuing];
+
+    match states.iter().find(|state| format!("{:?}", state).eq_ignore_ascii_case(name)) {
+        Some(state) => Ok(*state),
+        None => Err(error(line, &format!("unknown state: {}", name))),
+    }
+}
+
+fn parse_condition(line: usize, args: &[&str]) -> Result<Condition, ScriptError> {
+    if args.len() != 3 {
+        return Err(error(line, "expected 'if battery OP PERCENT' or 'if state OP STATE'"));
+    }
+
+    let comparison = match args[1] {
+        "<" => Comparison::Less,
+        "<=" => Comparison::LessOrEqual,
+        ">" => Comparison::Greater,
+        ">=" => Comparison::GreaterOrEqual,
+        "==" => Comparison::Equal,
+        "!=" => Comparison::NotEqual,
+        op => { return Err(error(line, &format!("unknown comparison: {}", op))) }
+    };
+
+    match args[0] {
+        "battery" => match args[2].trim_end_matches('%').parse() {
+            Ok(level) => Ok(Condition::Battery(comparison, level)),
+            Err(_) => Err(error(line, "battery level must be a percentage")),
+        },
+        "state" => match comparison {
+            Comparison::Equal | Comparison::NotEqual => Ok(Condition::State(comparison, parse_state(line, args[2])?)),
+            _ => Err(error(line, "states can only be compared with == and !=")),
+        },
+        other => Err(error(line, &format!("unknown condition: {}", other))),
+    }
+}
+
+/* Parses lines up to the `end` or `else` closing the block opened at line `opened`,
+ * or up to the end of the script for the outermost block
+ */
+fn parse_block(lines: &[(usize, &str)], pos: &mut usize, opened: Option<usize>)
+    -> Result<(Vec<ScriptLine>, Closing), ScriptError> {
+
+    let mut block = Vec::new();
+
+    while *pos < lines.len() {
+        let (line, text) = lines[*pos];
+        let args: Vec<&str> = text.split_whitespace().collect();
+        *pos += 1;
+
+        let statement = match args[0] {
+            "end" | "else" => {
+                if args.len() != 1 {
+                    return Err(error(line, &format!("'{}' takes no parameters", args[0])));
+                }
+
+                if opened.is_none() {
+                    return Err(error(line, &format!("'{}' without 'repeat' or 'if'", args[0])));
+                }
+
+                let closing = if args[0] == "end" { Closing::End } else { Closing::Else };
+                return Ok((block, closing));
+            },
+            "repeat" => {
+                let count = match args.len() {
+                    1 => 0,
+                    2 => match args[1].parse() {
+                        Ok(n) if n > 0 => n,
+                        _ => { return Err(error(line, "'repeat' takes a number of times above 0")) }
+                    },
+                    _ => { return Err(error(line, "'repeat' takes at most 1 parameter")) }
+                };
+
+                let (body, closing) = parse_block(lines, pos, Some(line))?;
+
+                if closing != Closing::End {
+                    return Err(error(line, "'repeat' must be closed with 'end'"));
+                }
+
+                Statement::Repeat { count, body }
+            },
+            "if" => {
+                let condition = parse_condition(line, &args[1..])?;
+                let (then, closing) = parse_block(lines, pos, Some(line))?;
+
+                let otherwise = if closing == Closing::Else {
+                    match parse_block(lines, pos, Some(line))? {
+                        (otherwise, Closing::End) => otherwise,
+                        _ => { return Err(error(line, "'else' must be closed with 'end'")) }
+                    }
+                } else {
+                    Vec::new()
+                };
+
+                Statement::If { condition, then, otherwise }
+            },
+            "goto" => match args.len() {
+                2 => Statement::Goto(GotoTarget::Waypoint(args[1].to_owned())),
+                3 => match (args[1].parse(), args[2].parse()) {
+                    (Ok(x), Ok(y)) => Statement::Goto(GotoTarget::Position(x, y)),
+                    _ => { return Err(error(line, "coordinates must be whole numbers (mm)")) }
+                },
+                _ => { return Err(error(line, "'goto' takes a waypoint NAME, or X Y")) }
+            },
+            "wait-arrival" => match args.len() {
+                1 => Statement::WaitArrival { timeout: Duration::new(WAIT_ARRIVAL_SECS, 0) },
+                2 => Statement::WaitArrival { timeout: parse_secs(line, args[1])? },
+                _ => { return Err(error(line, "'wait-arrival' takes at most 1 parameter")) }
+            },
+            "wait-state" => match args.len() {
+                2 => Statement::WaitState { state: parse_state(line, args[1])?, timeout: Duration::new(WAIT_STATE_SECS, 0) },
+                3 => Statement::WaitState { state: parse_state(line, args[1])?, timeout: parse_secs(line, args[2])? },
+                _ => { return Err(error(line, "'wait-state' takes a STATE, and optionally a timeout")) }
+            },
+            "sleep" => match args.len() {
+                2 => Statement::Sleep(parse_secs(line, args[1])?),
+                _ => { return Err(error(line, "'sleep' takes 1 parameter")) }
+            },
+            _ => Statement::Command(args.iter().map(|a| a.to_string()).collect()),
+        };
+
+        block.push(ScriptLine { line, text: args.join(" "), statement });
+    }
+
+    match opened {
+        Some(line) => Err(error(line, "block is never closed with 'end'")),
+        None => Ok((block, Closing::Eof)),
+    }
+}
+
+impl Script {
+    /* Parses a script. Everything after a # is a comment, and indentation is ignored
+     */
+    pub fn parse(script: &str) -> Result<Script, ScriptError> {
+        let lines: Vec<(usize, &str)> = script.lines()
+            .map(|line| line.split('#').next().unwrap_or("").trim())
+            .enumerate()
+            .map(|(i, line)| (i + 1, line))
+            .filter(|&(_, line)| !line.is_empty())
+            .collect();
+
+        let (lines, _) = parse_block(&lines, &mut 0, None)?;
+
+        Ok(Script { lines })
+    }
+
+    /* Reads and parses a script file
+     */
+    pub fn from_file(script_path: &str) -> Result<Script, ScriptError> {
+        let mut script_file = match File::open(script_path) {
+            Ok(s) => s,
+            Err(e) => { return Err(error(0, &format!("unable to open script file: {}", e))) }
+        };
+
+        let mut script = String::new();
+
+        if let Err(e) = script_file.read_to_string(&mut script) {
+            return Err(error(0, &format!("unable to read script file: {}", e)));
+        }
+
+        Script::parse(&script)
+    }
+}
+
+impl Comparison {
+    fn holds<T: PartialOrd>(&self, a: T, b: T) -> bool {
+        match *self {
+            Comparison::Less => a < b,
+            Comparison::LessOrEqual => a <= b,
+            Comparison::Greater => a > b,
+            Comparison::GreaterOrEqual => a >= b,
+            Comparison::Equal => a == b,
+            Comparison::NotEqual => a != b,
+        }
+    }
+}
+
+impl<'a, C, F> Runner<'a, C, F>
+    where C: FnMut(&mut Robot, &[String]) -> Result<(), String>, F: FnMut(&ScriptStep) {
+
+    fn report(&mut self, line: &ScriptLine, result: Result<String, String>) -> Result<(), ScriptError> {
+        (self.on_step)(&ScriptStep { line: line.line, text: line.text.clone(), result: result.clone() });
+
+        match result {
+            Ok(_) => Ok(()),
+            Err(message) => Err(ScriptError { line: line.line, message }),
+        }
+    }
+
+    fn run_block(&mut self, block: &[ScriptLine]) -> Result<(), ScriptError> {
+        for line in block {
+            match line.statement {
+                Statement::Repeat { count, ref body } => {
+                    let mut lap = 0;
+
+                    while count == 0 || lap < count {
+                        lap += 1;
+
+                        let note = if count == 0 { format!("lap {}", lap) } else { format!("lap {} of {}", lap, count) };
+                        self.report(line, Ok(note))?;
+                        self.run_block(body)?;
+                    }
+                },
+                Statement::If { ref condition, ref then, ref otherwise } => {
+                    let holds = self.check(condition);
+                    self.report(line, holds.clone().map(|h| h.to_string()))?;
+
+                    if holds == Ok(true) {
+                        self.run_block(then)?;
+                    } else {
+                        self.run_block(otherwise)?;
+                    }
+                },
+                ref statement => {
+                    let result = self.step(statement);
+                    self.report(line, result)?;
+                },
+            }
+        }
+
+        Ok(())
+    }
+
+    fn check(&mut self, condition: &Condition) -> Result<bool, String> {
+        match *condition {
+            Condition::Battery(comparison, level) => match self.robot.get_battery() {
+                Ok(battery) => Ok(comparison.holds(battery.percentage, level)),
+                Err(e) => Err(format!("unable to read battery status: {}", e)),
+            },
+            Condition::State(comparison, state) => match self.robot.get_state() {
+                Ok(current) => Ok((current == state) == (comparison == Comparison::Equal)),
+                Err(e) => Err(format!("unable to read state: {}", e)),
+            },
+        }
+    }
+
+    fn step(&mut self, statement: &Statement) -> Result<String, String> {
+        match *statement {
+            Statement::Command(ref args) => (self.command)(self.robot, args).map(|_| String::from("ok")),
+            Statement::Goto(ref target) => {
+                let (x, y, heading) = match *target {
+                    GotoTarget::Waypoint(ref name) => match self.robot.config.get_waypoint(name) {
+                        Ok(w) => (w.x, w.y, w.heading),
+                        Err(_) => { return Err(format!("unknown waypoint: {}", name)) }
+                    },
+                    GotoTarget::Position(x, y) => (x, y, None),
+                };
+
+                // Subscribe first, not to miss the robot setting off
+                let rx = self.robot.subscribe();
+                let telemetry = self.robot.telemetry.lock().unwrap().clone();
+
+                if let Err(e) = self.robot.goto(x, y) {
+                    return Err(format!("unable to send command: {}", e));
+                }
+
+                self.arrival = Some(Arrival { rx, telemetry, x, y, heading });
+                Ok(String::from("ok"))
+            },
+            Statement::WaitArrival { timeout } => {
+                let arrival = match self.arrival.take() {
+                    Some(a) => a,
+                    None => { return Err(String::from("no goto to wait for")) }
+                };
+
+                let deadline = Instant::now() + timeout;
+
+                match arrival::wait_for_arrival(&arrival.rx, &arrival.telemetry, arrival.x, arrival.y,
+                                                ARRIVAL_TOLERANCE_MM, timeout) {
+                    Ok(GotoOutcome::Arrived) => {},
+                    Ok(outcome) => {
+                        let _ = self.robot.stop();
+                        return Err(format!("did not arrive: {:?}", outcome));
+                    },
+                    Err(e) => { return Err(format!("unable to follow the robot: {}", e.with_address(self.robot.address()))) }
+                }
+
+                if let Some(heading) = arrival.heading {
+                    let remaining = deadline.saturating_duration_since(Instant::now());
+
+                    if self.robot.turn_to(Angle::from_degrees(heading), remaining).is_err() {
+                        let _ = self.robot.stop();
+                        return Err(String::from("did not turn to the heading of the waypoint"));
+                    }
+                }
+
+                Ok(String::from("arrived"))
+            },
+            Statement::WaitState { state, timeout } => match self.robot.wait_for_state(state, timeout) {
+                Ok(_) => Ok(String::from("ok")),
+                Err(e) => match e.err_type {
+                    RobotErrorType::Timeout => Err(format!("still not {:?}", state)),
+                    _ => Err(format!("unable to follow the robot: {}", e)),
+                },
+            },
+            Statement::Sleep(duration) => {
+                thread::sleep(duration);
+                Ok(String::from("ok"))
+            },
+            // Blocks are run by run_block
+            Statement::Repeat { .. } | Statement::If { .. } => Ok(String::new()),
+        }
+    }
+}
+
+/* Runs a script, see PuluRobot::run_script. Returns the line that failed, and why.
+ * A robot that doesn't arrive is stopped.
+ */
+pub fn run<C, F>(robot: &mut Robot, script: &Script, command: C, on_step: F) -> Result<(), ScriptError>
+    where C: FnMut(&mut Robot, &[String]) -> Result<(), String>, F: FnMut(&ScriptStep) {
+
+    let mut runner = Runner { robot, command, on_step, arrival: None };
+
+    runner.run_block(&script.lines)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn line(line: usize, text: &str, statement: Statement) -> ScriptLine {
+        ScriptLine { line, text: text.to_owned(), statement }
+    }
+
+    #[test]
+    fn parses_blocks() {
+        let script = Script::parse("
+            # Patrol
+            localize
+            repeat 2
+                goto kitchen   # next to the fridge
+                wait-arrival 90
+                if battery < 20%
+                    goto 100 -200
+                else
+                    sleep 1.5
+                end
+            end
+            wait-state Idle
+        ").unwrap();
+
+        assert_eq!(script.lines, vec![
+            line(3, "localize", Statement::Command(vec![String::from("localize")])),
+            line(4, "repeat 2", Statement::Repeat { count: 2, body: vec![
+                line(5, "goto kitchen", Statement::Goto(GotoTarget::Waypoint(String::from("kitchen")))),
+                line(6, "wait-arrival 90", Statement::WaitArrival { timeout: Duration::new(90, 0) }),
+                line(7, "if battery < 20%", Statement::If {
+                    condition: Condition::Battery(Comparison::Less, 20),
+                    then: vec![line(8, "goto 100 -200", Statement::Goto(GotoTarget::Position(100, -200)))],
+                    otherwise: vec![line(10, "sleep 1.5", Statement::Sleep(Duration::from_millis(1500)))],
+                }),
+            ]}),
+            line(13, "wait-state Idle", Statement::WaitState { state: RobotState::Idle, timeout: Duration::new(60, 0) }),
+        ]);
+    }
+
+    #[test]
+    fn reports_errors_with_line_numbers() {
+        assert_eq!(Script::parse("repeat\ngoto a").err(), Some(error(1, "block is never closed with 'end'")));
+        assert_eq!(Script::parse("stop\nend").err(), Some(error(2, "'end' without 'repeat' or 'if'")));
+        assert_eq!(Script::parse("\n\nif state < idle\nend").err(), Some(error(3, "states can only be compared with == and !=")));
+        assert_eq!(Script::parse("wait-state asleep").err(), Some(error(1, "unknown state: asleep")));
+        assert_eq!(Script::parse("sleep soon").err(), Some(error(1, "expected a number of seconds")));
+        assert_eq!(Script::parse("repeat 0\nend").err(), Some(error(1, "'repeat' takes a number of times above 0")));
+    }
+
+    /* Names of the waypoints a block sends the robot to, blocks within included */
+    fn waypoints(block: &[ScriptLine], names: &mut Vec<String>) {
+        for line in block {
+            match line.statement {
+                Statement::Goto(GotoTarget::Waypoint(ref name)) => names.push(name.clone()),
+                Statement::Repeat { ref body, .. } => waypoints(body, names),
+                Statement::If { ref then, ref otherwise, .. } => {
+                    waypoints(then, names);
+                    waypoints(otherwise, names);
+                },
+                _ => {},
+            }
+        }
+    }
+
+    #[test]
+    fn sample_visits_sample_waypoints() {
+        let script = Script::from_file("config/script.sample").unwrap();
+        let config = Config::from_file("config/config.sample").ok().unwrap();
+
+        let mut names = Vec::new();
+        waypoints(&script.lines, &mut names);
+
+        assert_eq!(names, vec!["a", "b", "dock"]);
+        assert!(names.iter().all(|name| config.get_waypoint(name).is_ok()));
+    }
+
+    #[test]
+    fn runs_against_simulator() {
+        let settings = SimulatorSettings { speed: 2000.0, turn_speed: 720.0, interval_ms: 20, ..SimulatorSettings::default() };
+        let simulator = Simulator::start("127.0.0.1:0", settings).ok().unwrap();
+
+        let mut config = Config::new();
+        config.robot_address = simulator.local_addr().ip().to_string();
+        config.robot_port = simulator.local_addr().port().to_string();
+
+        let mut robot = Robot::with_config(config, "").ok().unwrap();
+
+        let script = Script::parse("
+            goto 0 400
+            wait-arrival 10
+            repeat 2
+                if state == idle
+                    hello
+                end
+            end
+            wait-arrival
+        ").unwrap();
+
+        let mut commands = Vec::new();
+        let mut steps = Vec::new();
+
+        let result = robot.run_script(&script, |_, args| {
+            commands.push(args.join(" "));
+            Ok(())
+        }, |step| steps.push((step.line, step.result.clone())));
+
+        assert_eq!(commands, vec!["hello", "hello"]);
+        assert_eq!(simulator.pose().location(), RobotLocation { x: 0, y: 400 });
+        assert_eq!(result, Err(error(9, "no goto to wait for")));
+        assert_eq!(steps[1], (3, Ok(String::from("arrived"))));
+        assert_eq!(steps[2], (4, Ok(String::from("lap 1 of 2"))));
+        assert_eq!(steps[3], (5, Ok(String::from("true"))));
+        assert_eq!(steps.len(), 9);
+    }
+}
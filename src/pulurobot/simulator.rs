@@ -0,0 +1,443 @@
+
+/* A simulated robot speaking the robot's TCP protocol */
+
+use super::*;
+use std::net::{TcpListener, Shutdown};
+use std::io::Write;
+use std::sync::atomic::Ordering;
+use std::thread;
+
+/* Footprint of the robot, as sent in the 140 message */
+const ROBOT_INFO: RobotInfo = RobotInfo { xs: 480, ys: 400, xoffs: -120, yoffs: 0 };
+
+/* Distance and angle covered by a single manual step */
+const MANUAL_STEP_MM: f64 = 100.0;
+const MANUAL_STEP_DEG: f64 = 10.0;
+
+/* The height maps sent: cells on a side, their size in mm, and how often */
+const HEIGHT_MAP_SAMPS: u16 = 32;
+const HEIGHT_MAP_UNIT_MM: u8 = 40;
+const HEIGHT_MAP_INTERVAL_MS: u64 = 1000;
+
+/* Battery drain and charge, in percent per second */
+const DRAIN_IDLE: f64 = 0.01;
+const DRAIN_DRIVING: f64 = 0.05;
+const CHARGE_RATE: f64 = 1.0;
+
+impl Default for SimulatorSettings {
+    fn default() -> Self {
+        SimulatorSettings {
+            speed: 500.0,
+            turn_speed: 90.0,
+            interval_ms: 100,
+            start: RobotPose { x: 0, y: 0, angle: Angle::default() },
+        }
+    }
+}
+
+impl SimulatedRobot {
+    fn new(start: &RobotPose) -> Self {
+        SimulatedRobot {
+            x: start.x as f64,
+            y: start.y as f64,
+            angle: start.angle.degrees(),
+            state: RobotState::Idle,
+            target: None,
+            reverse: false,
+            battery: 100.0,
+            charging: false,
+        }
+    }
+
+    fn pose(&self) -> RobotPose {
+        RobotPose {
+            x: self.x.round() as i32,
+            y: self.y.round() as i32,
+            angle: Angle::from_degrees(self.angle),
+        }
+    }
+
+    fn battery(&self) -> BatteryStatus {
+        BatteryStatus {
+            charging: self.charging,
+            finished: self.charging && self.battery >= 100.0,
+            // Roughly 13.0V when empty, 16.5V when full
+            voltage: (13000.0 + self.battery * 35.0) as u16,
+            percentage: self.battery.round() as u8,
+        }
+    }
+
+    /* What the sensors would see in an empty room: floor ahead of the robot, and
+     * nothing behind it
+     */
+    fn height_map(&self) -> HeightMap {
+        let middle = HEIGHT_MAP_SAMPS as usize / 2;
+        let mut cells = Vec::new();
+
+        for _ in 0..HEIGHT_MAP_SAMPS {
+            for x in 0..HEIGHT_MAP_SAMPS as usize {
+                cells.push(if x >= middle { HeightMapCell::Floor } else { HeightMapCell::Unseen });
+            }
+        }
+
+        let pose = self.pose();
+
+        HeightMap {
+            xsamps: HEIGHT_MAP_SAMPS,
+            ysamps: HEIGHT_MAP_SAMPS,
+            angle: pose.angle,
+            xorig: pose.x,
+            yorig: pose.y,
+            unit_size: HEIGHT_MAP_UNIT_MM,
+            cells,
+        }
+    }
+
+    fn stop(&mut self) {
+        self.target = None;
+        self.reverse = false;
+        self.charging = false;
+        self.state = RobotState::Idle;
+    }
+
+    fn apply(&mut self, command: &Command) {
+        match *command {
+            Command::Dest { x, y, backwards } => {
+                self.stop();
+                self.target = Some((x as f64, y as f64));
+                self.reverse = backwards;
+                self.state = RobotState::Think;
+            },
+            Command::Route { x, y } => {
+                self.stop();
+                self.target = Some((x as f64, y as f64));
+                self.state = RobotState::Think;
+            },
+            Command::Charge => {
+                self.stop();
+                self.charging = true;
+                self.state = RobotState::Charging;
+            },
+            Command::Stop | Command::Free | Command::Localize | Command::Mode(_) => self.stop(),
+            Command::Manual(direction) => {
+                self.stop();
+
+                let heading = self.angle.to_radians();
+
+                match direction {
+                    ManualDirection::Forward => {
+                        self.x += MANUAL_STEP_MM * heading.cos();
+                        self.y += MANUAL_STEP_MM * heading.sin();
+                    },
+                    ManualDirection::Backward => {
+                        self.x -= MANUAL_STEP_MM * heading.cos();
+                        self.y -= MANUAL_STEP_MM * heading.sin();
+                    },
+                    ManualDirection::Left => self.angle = Angle::from_degrees(self.angle + MANUAL_STEP_DEG).degrees(),
+                    ManualDirection::Right => self.angle = Angle::from_degrees(self.angle - MANUAL_STEP_DEG).degrees(),
+                }
+            },
+            Command::SpeedLimit(_) => {},
+        }
+    }
+
+    /* Advances the simulation by `dt` seconds: turns towards the target, or away from it
+     * when reversing, then drives to it
+     */
+    fn step(&mut self, settings: &SimulatorSettings, dt: f64) {
+        if self.charging {
+            self.battery = (self.battery + CHARGE_RATE * dt).min(100.0);
+        } else if self.target.is_some() {
+            self.battery = (self.battery - DRAIN_DRIVING * dt).max(0.0);
+        } else {
+            self.battery = (self.battery - DRAIN_IDLE * dt).max(0.0);
+        }
+
+        let (tx, ty) = match self.target {
+            Some(t) => t,
+            None => return,
+        };
+
+        let (dx, dy) = (tx - self.x, ty - self.y);
+        let distance = dx.hypot(dy);
+
+        if distance < 1.0 {
+            self.x = tx;
+            self.y = ty;
+            self.stop();
+            return;
+        }
+
+        let mut heading = Angle::from_radians(dy.atan2(dx));
+
+        if self.reverse {
+            heading = Angle::from_degrees(heading.degrees() + 180.0);
+        }
+
+        let error = heading.difference(Angle::from_degrees(self.angle)).degrees();
+        let max_turn = settings.turn_speed * dt;
+
+        if error.abs() > 1.0 {
+            self.angle = Angle::from_degrees(self.angle + error.signum() * error.abs().min(max_turn)).degrees();
+            self.state = if error > 0.0 { RobotState::Left } else { RobotState::Right };
+        } else {
+            let travel = (settings.speed * dt).min(distance);
+
+            self.angle = heading.degrees();
+            self.x += travel * dx / distance;
+            self.y += travel * dy / distance;
+            self.state = if self.reverse { RobotState::Rev } else { RobotState::Fwd };
+        }
+    }
+}
+
+impl Simulator {
+    /* Starts simulating a robot listening on `addr`, e.g. "127.0.0.1:22222". Port 0 picks
+     * a free port, see local_addr.
+     */
+    pub fn start(addr: &str, settings: SimulatorSettings) -> Result<Simulator, RobotError> {
+        let listener = match TcpListener::bind(addr) {
+            Ok(s) => s,
+            Err(e) => { return Err( RobotError::new(RobotErrorType::Connection).with_address(addr).with_source(e) ) }
+        };
+
+        let addr = match listener.local_addr() {
+            Ok(s) => s,
+            Err(e) => { return Err( RobotError::new(RobotErrorType::Connection).with_address(addr).with_source(e) ) }
+        };
+
+        let robot = Arc::new(Mutex::new(SimulatedRobot::new(&settings.start)));
+        let running = Arc::new(AtomicBool::new(true));
+        let clients: Arc<Mutex<Vec<TcpStream>>> = Arc::new(Mutex::new(Vec::new()));
+
+        {
+            let robot = Arc::clone(&robot);
+            let running = Arc::clone(&running);
+            let clients = Arc::clone(&clients);
+
+            thread::spawn(move || accept_clients(listener, robot, running, clients));
+        }
+
+        let thread = {
+            let robot = Arc::clone(&robot);
+            let running = Arc::clone(&running);
+
+            thread::spawn(move || simulate(settings, robot, running, clients))
+        };
+
+        Ok(Simulator {
+            addr,
+            robot,
+            running,
+            thread: Some(thread),
+        })
+    }
+
+    pub fn local_addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    /* The exact position of the simulated robot
+     */
+    pub fn pose(&self) -> RobotPose {
+        self.robot.lock().unwrap().pose()
+    }
+
+    /* Blocks until the simulator is stopped
+     */
+    pub fn wait(mut self) {
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+
+    /* Stops the simulation, and disconnects all clients
+     */
+    pub fn stop(&mut self) {
+        self.running.store(false, Ordering::SeqCst);
+
+        // Wake up the accepting thread, so it notices
+        let _ = TcpStream::connect(self.addr);
+
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+impl Drop for Simulator {
+    fn drop(&mut self) {
+        self.stop();
+    }
+}
+
+fn accept_clients(listener: TcpListener, robot: Arc<Mutex<SimulatedRobot>>, running: Arc<AtomicBool>,
+                  clients: Arc<Mutex<Vec<TcpStream>>>) {
+    for stream in listener.incoming() {
+        if !running.load(Ordering::SeqCst) {
+            break;
+        }
+
+        let mut stream = match stream {
+            Ok(s) => s,
+            Err(_) => continue,
+        };
+
+        if stream.write_all(&Message::RobotInfo(ROBOT_INFO).encode()).is_err() {
+            continue;
+        }
+
+        if let Ok(s) = stream.try_clone() {
+            clients.lock().unwrap().push(s);
+        }
+
+        let robot = Arc::clone(&robot);
+
+        thread::spawn(move || {
+            let mut reader = FrameReader::new(stream);
+
+            loop {
+                let frame = match reader.read_frame() {
+                    Ok(f) => f,
+                    // Garbage was skipped, carry on with the next frame
+                    Err(RobotError { protocol: Some(_), .. }) => continue,
+                    Err(_) => break,
+                };
+
+                if let Ok(command) = Command::from_payload(frame.opcode, &frame.payload) {
+                    robot.lock().unwrap().apply(&command);
+                }
+            }
+        });
+    }
+}
+
+fn simulate(settings: SimulatorSettings, robot: Arc<Mutex<SimulatedRobot>>, running: Arc<AtomicBool>,
+            clients: Arc<Mutex<Vec<TcpStream>>>) {
+    let interval = Duration::from_millis(settings.interval_ms);
+    let mut last = Instant::now();
+    let mut last_height_map = last;
+
+    while running.load(Ordering::SeqCst) {
+        thread::sleep(interval);
+
+        let now = Instant::now();
+        let dt = (now - last).as_secs() as f64 + (now - last).subsec_nanos() as f64 / 1e9;
+        last = now;
+
+        let mut telemetry = Vec::new();
+
+        {
+            let mut robot = robot.lock().unwrap();
+            robot.step(&settings, dt);
+
+            telemetry.extend(Message::Position(robot.pose()).encode());
+            telemetry.extend(Message::Battery(robot.battery()).encode());
+            telemetry.extend(Message::State(robot.state).encode());
+
+            if now - last_height_map >= Duration::from_millis(HEIGHT_MAP_INTERVAL_MS) {
+                telemetry.extend(Message::TofHeightMap(robot.height_map()).encode());
+                last_height_map = now;
+            }
+        }
+
+        // Drop clients which have hung up
+        clients.lock().unwrap().retain(|client| (&*client).write_all(&telemetry).is_ok());
+    }
+
+    for client in clients.lock().unwrap().drain(..) {
+        let _ = client.shutdown(Shutdown::Both);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pulurobot::pulurobot::COMMAND_LATENCY_MS;
+
+    fn fast_settings() -> SimulatorSettings {
+        SimulatorSettings {
+            speed: 5000.0,
+            turn_speed: 3600.0,
+            interval_ms: 10,
+            start: RobotPose { x: 0, y: 0, angle: Angle::default() },
+        }
+    }
+
+    #[test]
+    fn drives_to_target() {
+        let mut robot = SimulatedRobot::new(&fast_settings().start);
+        robot.apply(&Command::Route { x: 0, y: 1000 });
+
+        for _ in 0..100 {
+            robot.step(&fast_settings(), 0.01);
+        }
+
+        assert_eq!(robot.state, RobotState::Idle);
+        assert_eq!(robot.pose().location(), RobotLocation { x: 0, y: 1000 });
+        assert!((robot.pose().angle.degrees() - 90.0).abs() < 0.1);
+    }
+
+    #[test]
+    fn reverses_to_dest() {
+        let mut robot = SimulatedRobot::new(&fast_settings().start);
+        robot.apply(&Command::Dest { x: -500, y: 0, backwards: true });
+        robot.step(&fast_settings(), 0.01);
+
+        assert_eq!(robot.state, RobotState::Rev);
+
+        for _ in 0..100 {
+            robot.step(&fast_settings(), 0.01);
+        }
+
+        assert_eq!(robot.pose().location(), RobotLocation { x: -500, y: 0 });
+        assert_eq!(robot.pose().angle.degrees(), 0.0);
+    }
+
+    #[test]
+    fn robot_arrives_over_tcp() {
+        let simulator = Simulator::start("127.0.0.1:0", fast_settings()).ok().unwrap();
+
+        let mut config = Config::new();
+        config.robot_address = simulator.local_addr().ip().to_string();
+        config.robot_port = simulator.local_addr().port().to_string();
+
+        let mut robot = Robot::with_config(config, "").ok().unwrap();
+        let outcome = robot.goto_and_wait(300, -400, 50, Duration::new(10, 0)).ok();
+
+        assert_eq!(outcome, Some(GotoOutcome::Arrived));
+        assert_eq!(simulator.pose().location(), RobotLocation { x: 300, y: -400 });
+        assert_eq!(robot.get_state().ok(), Some(RobotState::Idle));
+
+        // Nudges relative to where it ended up, facing away from the origin
+        assert!(robot.forward(-500).is_ok());
+        assert_eq!(robot.wait_for_state(RobotState::Idle, Duration::new(5, 0)).ok(), Some(()));
+        assert_eq!(simulator.pose().location(), RobotLocation { x: 0, y: 0 });
+
+        assert!(robot.move_by(100, 200).is_ok());
+        thread::sleep(Duration::from_millis(500));
+        assert_eq!(simulator.pose().location(), RobotLocation { x: 100, y: 200 });
+    }
+
+    #[test]
+    fn waits_for_idle_after_goto() {
+        let simulator = Simulator::start("127.0.0.1:0", fast_settings()).ok().unwrap();
+
+        let mut config = Config::new();
+        config.robot_address = simulator.local_addr().ip().to_string();
+        config.robot_port = simulator.local_addr().port().to_string();
+
+        let mut robot = Robot::with_config(config, "").ok().unwrap();
+        assert_eq!(robot.get_state().ok(), Some(RobotState::Idle));
+
+        assert!(robot.goto(1000, 500).is_ok());
+        assert_eq!(robot.wait_for_state(RobotState::Idle, Duration::new(10, 0)).ok(), Some(()));
+        assert_eq!(simulator.pose().location(), RobotLocation { x: 1000, y: 500 });
+
+        // Long after the last command, being idle already is enough
+        thread::sleep(Duration::from_millis(COMMAND_LATENCY_MS));
+        let start = Instant::now();
+        assert_eq!(robot.wait_for_state(RobotState::Idle, Duration::new(10, 0)).ok(), Some(()));
+        assert!(start.elapsed() < Duration::from_millis(100));
+    }
+}
@@ -0,0 +1,54 @@
+/*
+ * Serves a capture recorded by the console back over TCP, as if it was the robot
+ */
+
+extern crate pulurobot_robot;
+
+use std::env;
+use std::process;
+
+use pulurobot_robot::pulurobot::{CaptureRecord, Replayer, RobotError};
+
+fn main() {
+    let args: Vec<String> = env::args().collect();
+
+    if args.len() < 2 {
+        eprintln!("Usage: replay FILE [ADDRESS:PORT] [SPEED]");
+        process::exit(2);
+    }
+
+    let addr = match args.get(2) {
+        Some(s) => s.to_owned(),
+        None => String::from("127.0.0.1:22222"),
+    };
+
+    let speed = match args.get(3).map(|s| s.parse()) {
+        Some(Ok(s)) => s,
+        Some(Err(_)) => {
+            eprintln!("Usage: replay FILE [ADDRESS:PORT] [SPEED]");
+            process::exit(2);
+        },
+        None => 1.0,
+    };
+
+    let records = match CaptureRecord::load(&args[1]) {
+        Ok(s) => s,
+        Err(e) => {
+            let e = RobotError::from(e);
+            eprintln!("{}", e);
+            process::exit(e.exit_code());
+        }
+    };
+
+    let replayer = match Replayer::start(&addr, records, speed) {
+        Ok(s) => s,
+        Err(e) => {
+            eprintln!("Unable to replay: {}", e);
+            process::exit(e.exit_code());
+        }
+    };
+
+    println!("Replaying {} on {}", args[1], replayer.local_addr());
+
+    replayer.wait();
+}
@@ -3,19 +3,13 @@
  * Author: Brian Alberg <brian@alberg.org>
  */
 
-#[macro_use]
-extern crate serde_derive;
-extern crate serde;
-extern crate serde_json;
-extern crate byteorder;
-
-mod pulurobot;
+extern crate pulurobot_robot;
 
 use std::io::{BufWriter,Write,BufReader,BufRead};
 use std::io;
 use std::path::Path;
 
-use pulurobot::{Config, ConfigHandler};
+use pulurobot_robot::pulurobot::{Config, ConfigHandler, generate_key};
 
 fn main() {
 
@@ -23,12 +17,10 @@ fn main() {
 
     let mut writer = BufWriter::new(io::stdout());
     let config_path = "config/config";
-    let mut config:Config = Config::new();
-
     // Check if config file exists
-    if Path::new(config_path).exists() {
+    let mut config:Config = if Path::new(config_path).exists() {
         // Try to open
-        config = match config.from_file(config_path) {
+        match Config::from_file(config_path) {
             Ok(s) => s,
             Err(_) => {
                 // On fail, create new config file
@@ -40,20 +32,20 @@ fn main() {
         }
     } else {
         // Create new config file
-        config = match Config::create(config_path) {
+        match Config::create(config_path) {
             Ok(s) => s,
             Err(_) => panic!("Unable to create configuration file"),
-        };
-    }
+        }
+    };
 
     let mut reader = BufReader::new(io::stdin());
     let mut read_buffer = String::new();
 
     // Handle Name
     if config.name.is_empty() {
-        writer.write("Name of robot: ".as_bytes()).unwrap();
+        writer.write_all("Name of robot: ".as_bytes()).unwrap();
     } else {
-        writer.write((String::from("Name of robot [") + &config.name + "]: ").as_bytes()).unwrap();
+        writer.write_all((String::from("Name of robot [") + &config.name + "]: ").as_bytes()).unwrap();
     }
     writer.flush().unwrap();
 
@@ -68,9 +60,9 @@ fn main() {
 
     // Handle Manufacturer
     if config.manufacturer.is_empty() {
-        writer.write("Manufacturer: ".as_bytes()).unwrap();
+        writer.write_all("Manufacturer: ".as_bytes()).unwrap();
     } else {
-        writer.write((String::from("Manufacturer: [") + &config.manufacturer + "]: ").as_bytes()).unwrap();
+        writer.write_all((String::from("Manufacturer: [") + &config.manufacturer + "]: ").as_bytes()).unwrap();
     }
     writer.flush().unwrap();
 
@@ -85,9 +77,9 @@ fn main() {
 
     // Handle Robot IP
     if config.manufacturer.is_empty() {
-        writer.write("Robot IP: ".as_bytes()).unwrap();
+        writer.write_all("Robot IP: ".as_bytes()).unwrap();
     } else {
-        writer.write((String::from("Robot IP [") + &config.robot_address + "]: ").as_bytes()).unwrap();
+        writer.write_all((String::from("Robot IP [") + &config.robot_address + "]: ").as_bytes()).unwrap();
     }
     writer.flush().unwrap();
 
@@ -102,9 +94,9 @@ fn main() {
 
     // Handle Robot Port
     if config.robot_port.is_empty() {
-        writer.write("Robot Port: ".as_bytes()).unwrap();
+        writer.write_all("Robot Port: ".as_bytes()).unwrap();
     } else {
-        writer.write((String::from("Robot Port [") + &config.robot_port + "]: ").as_bytes()).unwrap();
+        writer.write_all((String::from("Robot Port [") + &config.robot_port + "]: ").as_bytes()).unwrap();
     }
     writer.flush().unwrap();
 
@@ -117,18 +109,100 @@ fn main() {
 
     read_buffer.clear();
 
+    // Handle Server Address
+    if config.server_address.is_empty() {
+        writer.write_all("Server address (blank for none): ".as_bytes()).unwrap();
+    } else {
+        writer.write_all((String::from("Server address [") + &config.server_address + "]: ").as_bytes()).unwrap();
+    }
+    writer.flush().unwrap();
+
+    reader.read_line(&mut read_buffer).unwrap();
+    read_buffer.pop(); // Remove trailing newline
+
+    if !read_buffer.is_empty() {
+        config.server_address = read_buffer.clone();
+    }
+
+    read_buffer.clear();
+
+    // Handle Server Port
+    if !config.server_address.is_empty() {
+        if config.server_port.is_empty() {
+            writer.write_all("Server Port: ".as_bytes()).unwrap();
+        } else {
+            writer.write_all((String::from("Server Port [") + &config.server_port + "]: ").as_bytes()).unwrap();
+        }
+        writer.flush().unwrap();
+
+        reader.read_line(&mut read_buffer).unwrap();
+        read_buffer.pop(); // Remove trailing newline
+
+        if !read_buffer.is_empty() {
+            config.server_port = read_buffer.clone();
+        }
+
+        read_buffer.clear();
+
+        // Handle TLS
+        if config.server_tls {
+            writer.write_all("Connect to the server over wss:// (TLS)? [Y/n]: ".as_bytes()).unwrap();
+        } else {
+            writer.write_all("Connect to the server over wss:// (TLS)? [y/N]: ".as_bytes()).unwrap();
+        }
+        writer.flush().unwrap();
+
+        reader.read_line(&mut read_buffer).unwrap();
+        read_buffer.pop(); // Remove trailing newline
+
+        match read_buffer.as_str() {
+            "y" => config.server_tls = true,
+            "n" => config.server_tls = false,
+            _ => {},
+        }
+
+        read_buffer.clear();
+
+        // Handle Private Key
+        if config.private_key.is_empty() {
+            config.private_key = match generate_key() {
+                Ok(k) => k,
+                Err(_) => panic!("Unable to generate private key"),
+            };
+
+            println!("Generated private key for the server: {}", config.private_key);
+        } else {
+            writer.write_all("Generate new private key? [y/N]: ".as_bytes()).unwrap();
+            writer.flush().unwrap();
+
+            reader.read_line(&mut read_buffer).unwrap();
+            read_buffer.pop(); // Remove trailing newline
+
+            if read_buffer == "y" {
+                config.private_key = match generate_key() {
+                    Ok(k) => k,
+                    Err(_) => panic!("Unable to generate private key"),
+                };
+
+                println!("Generated private key for the server: {}", config.private_key);
+            }
+
+            read_buffer.clear();
+        }
+    }
+
     // Write to config file
-    writer.write("Writing to configuration file...".as_bytes()).unwrap();
+    writer.write_all("Writing to configuration file...".as_bytes()).unwrap();
     writer.flush().unwrap();
     match config.write(config_path) {
         Ok(_) => {         
-            writer.write("SUCCESS\n".as_bytes()).unwrap();
+            writer.write_all("SUCCESS\n".as_bytes()).unwrap();
             writer.flush().unwrap();
 
             println!("Robot Setup Completed. Have a nice day!");
         },
         Err(_) => {
-            writer.write("FAILED\n".as_bytes()).unwrap();
+            writer.write_all("FAILED\n".as_bytes()).unwrap();
             writer.flush().unwrap();
 
             println!("ERROR: Problem writing to configuration file.");
@@ -0,0 +1,43 @@
+/*
+ * Simulated robot, for trying out the clients without the real thing
+ */
+
+extern crate pulurobot_robot;
+
+use std::env;
+use std::process;
+
+use pulurobot_robot::pulurobot::{Simulator, SimulatorSettings};
+
+fn main() {
+    let args: Vec<String> = env::args().collect();
+
+    let addr = match args.get(1) {
+        Some(s) => s.to_owned(),
+        None => String::from("127.0.0.1:22222"),
+    };
+
+    let mut settings = SimulatorSettings::default();
+
+    if let Some(speed) = args.get(2) {
+        settings.speed = match speed.parse() {
+            Ok(s) => s,
+            Err(_) => {
+                eprintln!("Usage: simulator [ADDRESS:PORT] [SPEED]");
+                process::exit(2);
+            }
+        };
+    }
+
+    let simulator = match Simulator::start(&addr, settings) {
+        Ok(s) => s,
+        Err(e) => {
+            eprintln!("Unable to simulate: {}", e);
+            process::exit(e.exit_code());
+        }
+    };
+
+    println!("Simulating robot on {}", simulator.local_addr());
+
+    simulator.wait();
+}